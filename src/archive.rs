@@ -0,0 +1,878 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+use tracing::debug;
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+use crate::config::{Artifact, Compression};
+use crate::error::{DeployError, Result};
+use crate::manifest::Manifest;
+
+/// Size of the buffer artifacts are copied into the archive through, which
+/// bounds memory use whatever the size of the files.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Settings that decide how artifacts are written into the archive.
+#[derive(Debug)]
+pub struct ArchiveOptions {
+    pub compression: Compression,
+    /// Level for `compression`, its default when `None`.
+    pub compression_level: Option<i64>,
+    /// Extensions of files stored without compression, see
+    /// [`App::store_extensions`](crate::config::App::store_extensions).
+    pub store_extensions: Vec<String>,
+    /// Size limit in bytes for any single file going into the archive.
+    pub max_artifact_size: Option<u64>,
+    /// Size limit in bytes for the finished archive.
+    pub max_archive_size: Option<u64>,
+    /// Levels of subdirectories walked under directory artifacts.
+    pub max_depth: Option<usize>,
+    /// Let symlinks inside directory artifacts point outside the current
+    /// directory.
+    pub allow_external: bool,
+    /// Write entries sorted by name and dated 1980-01-01 instead of now, so
+    /// the same files always give a byte-identical archive.
+    pub reproducible: bool,
+}
+
+/// What went into a finished archive.
+#[derive(Debug)]
+pub struct ArchiveSummary {
+    /// Every file included, with its size and hash.
+    pub manifest: Manifest,
+    /// Size in bytes of the archive itself.
+    pub size: u64,
+}
+
+/// A file, or an empty directory, going into the archive.
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path inside the archive. Directory names end with `/`.
+    pub name: String,
+    pub source: PathBuf,
+    /// Size in bytes, 0 for directories.
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+/// Zips `artifacts` into `out`. Files are stored under `dest` or their file
+/// name, directory contents under `dest` or the archive root.
+pub fn build_archive(
+    artifacts: &[Artifact],
+    out: &Path,
+    opts: &ArchiveOptions,
+) -> Result<ArchiveSummary> {
+    let mut entries = collect_entries(
+        artifacts,
+        opts.max_artifact_size,
+        opts.max_depth,
+        opts.allow_external,
+    )?;
+    // Directories are otherwise listed in whatever order the filesystem
+    // returns them.
+    if opts.reproducible {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    let modified = if opts.reproducible {
+        DateTime::default()
+    } else {
+        DateTime::default_for_write()
+    };
+
+    let build_file = File::create(out).map_err(|err| {
+        DeployError::Build(format!(
+            "Error creating output file `{}`: {}",
+            out.display(),
+            err
+        ))
+    })?;
+
+    let mut zip_build = ZipWriter::new(&build_file);
+    let mut manifest = Manifest::default();
+    let options = SimpleFileOptions::default()
+        .compression_method(opts.compression.into())
+        .compression_level(opts.compression_level)
+        .last_modified_time(modified);
+
+    for entry in &entries {
+        debug!(entry = entry.name.as_str(), "adding archive entry");
+        if entry.is_dir {
+            zip_build
+                .add_directory(entry.name.as_str(), options)
+                .map_err(|err| {
+                    DeployError::Build(format!(
+                        "Error including empty directory `{}`: {}",
+                        entry.source.display(),
+                        err
+                    ))
+                })?;
+        } else {
+            let options = if is_stored(&entry.name, &opts.store_extensions) {
+                SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .last_modified_time(modified)
+            } else {
+                options
+            };
+            // Entries of 4 GiB or more need the ZIP64 extension, which has
+            // to be requested before their content is streamed.
+            let options = options.large_file(entry.size >= u64::from(u32::MAX));
+            zip_file(
+                &mut zip_build,
+                &mut manifest,
+                &entry.source,
+                &entry.name,
+                options,
+            )?;
+        }
+    }
+
+    zip_build.finish().map_err(|err| {
+        DeployError::Build(format!("Error writing to build output file: {}", err))
+    })?;
+
+    let size = build_file
+        .metadata()
+        .map_err(|err| {
+            DeployError::Build(format!("Error reading build output file size: {}", err))
+        })?
+        .len();
+    if let Some(max) = opts.max_archive_size.filter(|max| size > *max) {
+        return Err(DeployError::Build(format!(
+            "Archive `{}` is {} bytes, over the `max_archive_size` limit of {} bytes",
+            out.display(),
+            size,
+            max
+        )));
+    }
+
+    Ok(ArchiveSummary { manifest, size })
+}
+
+/// Writes the deploy file's directory as committed at `git_ref` to `out`
+/// with `git archive`, so only tracked files ship. Artifact size limits
+/// don't apply; `max_archive_size` does. The archive is reproducible as is,
+/// `git archive` dating entries with the commit's time.
+pub fn git_archive(git_ref: &str, out: &Path, opts: &ArchiveOptions) -> Result<ArchiveSummary> {
+    let inside =
+        git(&["rev-parse", "--is-inside-work-tree"]).is_ok_and(|output| output.trim() == "true");
+    if !inside {
+        return Err(DeployError::Config(
+            "`\"source\": \"git\"` needs the deploy file to be inside a git repository".to_string(),
+        ));
+    }
+    if git(&[
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        &format!("{}^{{commit}}", git_ref),
+    ])
+    .is_err()
+    {
+        return Err(DeployError::Config(format!(
+            "`git_ref` `{}` isn't a commit, branch or tag",
+            git_ref
+        )));
+    }
+
+    let level = match (opts.compression, opts.compression_level) {
+        (Compression::Stored, _) => Some(0),
+        (Compression::Deflated, level) => level,
+    };
+    let mut args = vec!["archive".to_string(), "--format=zip".to_string()];
+    if let Some(level) = level {
+        args.push(format!("-{}", level));
+    }
+    args.push(format!("--output={}", out.display()));
+    // Run from a subdirectory, `git archive` only includes that subtree.
+    args.push(git_ref.to_string());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    git(&args)
+        .map_err(|err| DeployError::Build(format!("Error running `git archive`: {}", err)))?;
+
+    let manifest = read_manifest(out)?;
+    let size = out
+        .metadata()
+        .map_err(|err| {
+            DeployError::Build(format!("Error reading build output file size: {}", err))
+        })?
+        .len();
+    if let Some(max) = opts.max_archive_size.filter(|max| size > *max) {
+        return Err(DeployError::Build(format!(
+            "Archive `{}` is {} bytes, over the `max_archive_size` limit of {} bytes",
+            out.display(),
+            size,
+            max
+        )));
+    }
+
+    Ok(ArchiveSummary { manifest, size })
+}
+
+/// Runs `git` with `args`, returning its stdout, or its stderr on failure.
+pub fn git(args: &[&str]) -> std::result::Result<String, String> {
+    debug!(?args, "running git");
+    let out = Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !out.status.success() {
+        return Err(String::from_utf8_lossy(&out.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Manifest of an archive written by something other than [`build_archive`].
+fn read_manifest(path: &Path) -> Result<Manifest> {
+    let error = |err: &dyn std::fmt::Display| {
+        DeployError::Build(format!(
+            "Error reading archive `{}`: {}",
+            path.display(),
+            err
+        ))
+    };
+    let file = File::open(path).map_err(|err| error(&err))?;
+    let mut archive = ZipArchive::new(file).map_err(|err| error(&err))?;
+    let mut manifest = Manifest::default();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).map_err(|err| error(&err))?;
+        if entry.is_dir() {
+            continue;
+        }
+        let (size, hasher) = copy_hashed(&mut entry, &mut io::sink()).map_err(|err| error(&err))?;
+        manifest.add(entry.name(), size, hasher);
+    }
+    Ok(manifest)
+}
+
+/// Whether the entry `name` has one of `extensions`, ignoring case.
+fn is_stored(name: &str, extensions: &[String]) -> bool {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase()))
+}
+
+/// Copies the entries of the archive at `full` named in `changed` into a new
+/// archive at `out`, as they are, for `--only-upload-changed-entries`.
+/// Directory entries are always copied, so new empty directories still
+/// reach the host.
+pub fn delta_archive(full: &Path, out: &Path, changed: &HashSet<&str>) -> Result<()> {
+    let error = |err: &dyn std::fmt::Display| {
+        DeployError::Build(format!(
+            "Error writing delta archive `{}`: {}",
+            out.display(),
+            err
+        ))
+    };
+    let file = File::open(full).map_err(|err| error(&err))?;
+    let mut archive = ZipArchive::new(file).map_err(|err| error(&err))?;
+    let delta = File::create(out).map_err(|err| error(&err))?;
+    let mut zip = ZipWriter::new(delta);
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i).map_err(|err| error(&err))?;
+        if entry.is_dir() || changed.contains(entry.name()) {
+            zip.raw_copy_file(entry).map_err(|err| error(&err))?;
+        }
+    }
+    zip.finish().map_err(|err| error(&err))?;
+    Ok(())
+}
+
+/// Resolves `artifacts` into the entries the archive will hold, in order,
+/// checking each file against `max_artifact_size`. Directory artifacts
+/// nesting subdirectories more than `max_depth` levels deep are an error, as
+/// are symlinks inside them that loop back or, unless `allow_external`,
+/// resolve outside the current directory.
+pub fn collect_entries(
+    artifacts: &[Artifact],
+    max_artifact_size: Option<u64>,
+    max_depth: Option<usize>,
+    allow_external: bool,
+) -> Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    let project = if allow_external {
+        None
+    } else {
+        let cwd = std::env::current_dir()
+            .and_then(|cwd| cwd.canonicalize())
+            .map_err(|err| {
+                DeployError::Other(format!("Error reading current directory: {}", err))
+            })?;
+        Some(cwd)
+    };
+
+    for artifact in artifacts {
+        let path = Path::new(artifact.src());
+
+        if path.is_file() && artifact.strip_prefix().is_some() {
+            return Err(DeployError::Config(format!(
+                "`strip_prefix` only applies to directory artifacts, use `dest` to rename `{}`",
+                artifact.src()
+            )));
+        }
+
+        if path.is_file() {
+            let name = match artifact.dest() {
+                Some(dest) => dest,
+                None => path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .ok_or_else(|| {
+                        DeployError::Config(format!(
+                            "Error getting artifact path `{}`",
+                            artifact.src()
+                        ))
+                    })?,
+            };
+            entries.push(file_entry(path, name.to_string(), max_artifact_size)?);
+        }
+
+        if path.is_dir() {
+            let prefix = match artifact.dest() {
+                Some(dest) => format!("{}/", dest.trim_end_matches('/')),
+                None => String::new(),
+            };
+            let walk = Walk {
+                root: path,
+                project: project.as_deref(),
+                max_artifact_size,
+                max_depth,
+            };
+            match artifact.strip_prefix() {
+                Some(strip) => {
+                    let mut dir_entries = Vec::new();
+                    collect_dir(&mut dir_entries, &walk, &mut Vec::new(), path, "", 0)?;
+                    for entry in dir_entries {
+                        entries.extend(strip_entry(artifact, entry, strip, &prefix)?);
+                    }
+                }
+                None => collect_dir(&mut entries, &walk, &mut Vec::new(), path, &prefix, 0)?,
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Renames `entry` of a directory artifact from under `strip` to under
+/// `prefix`, erroring when it isn't under `strip`. The directory entry for
+/// `strip` itself is dropped.
+fn strip_entry(
+    artifact: &Artifact,
+    mut entry: ArchiveEntry,
+    strip: &str,
+    prefix: &str,
+) -> Result<Option<ArchiveEntry>> {
+    let Some(rest) = entry
+        .name
+        .strip_prefix(strip)
+        .and_then(|rest| rest.strip_prefix('/'))
+    else {
+        return Err(DeployError::Config(format!(
+            "`{}` in artifact `{}` isn't under its `strip_prefix` `{}`",
+            entry.name,
+            artifact.src(),
+            strip
+        )));
+    };
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    entry.name = format!("{}{}", prefix, rest);
+    Ok(Some(entry))
+}
+
+/// Limits applying while walking the directory artifact at `root`.
+struct Walk<'a> {
+    root: &'a Path,
+    /// Directory symlinks must resolve under, `None` when they may point
+    /// anywhere.
+    project: Option<&'a Path>,
+    max_artifact_size: Option<u64>,
+    max_depth: Option<usize>,
+}
+
+/// Recursively collects the contents of `dir`, `depth` levels below the
+/// artifact's root, under `prefix`, adding directory entries for empty
+/// subdirectories so `unzip` recreates them. Symlinked directories are
+/// followed, `links` holding the targets of those already followed on the
+/// way down to `dir` so a link back into one of them is caught.
+fn collect_dir(
+    entries: &mut Vec<ArchiveEntry>,
+    walk: &Walk,
+    links: &mut Vec<PathBuf>,
+    dir: &Path,
+    prefix: &str,
+    depth: usize,
+) -> Result<()> {
+    if let Some(max) = walk.max_depth.filter(|max| depth > *max) {
+        return Err(DeployError::Build(format!(
+            "Artifact directory `{}` is {} level(s) below `{}`, over the `max_depth` limit of {}. \
+             Point the artifact at a narrower directory or raise `max_depth`",
+            dir.display(),
+            depth,
+            walk.root.display(),
+            max
+        )));
+    }
+
+    let mut is_empty = true;
+
+    let dir_entries = dir.read_dir().map_err(|err| {
+        DeployError::Build(format!(
+            "Error reading artifact directory `{}`: {}",
+            dir.display(),
+            err
+        ))
+    })?;
+
+    for e in dir_entries {
+        is_empty = false;
+        let entry = e.map_err(|err| {
+            DeployError::Build(format!(
+                "Error reading entry of artifact directory `{}`: {}",
+                dir.display(),
+                err
+            ))
+        })?;
+        let entry_path = entry.path();
+        let file_name = entry.file_name().into_string().map_err(|_| {
+            DeployError::Build(format!(
+                "Artifact `{}` has a file name that isn't valid UTF-8",
+                entry_path.display()
+            ))
+        })?;
+        let name = format!("{}{}", prefix, file_name);
+        let file_type = entry.file_type().map_err(|err| {
+            DeployError::Build(format!(
+                "Error reading artifact type `{}`: {}",
+                entry_path.display(),
+                err
+            ))
+        })?;
+
+        if file_type.is_symlink() {
+            let target = resolve_link(walk, &entry_path)?;
+            if target.is_dir() {
+                if links.contains(&target) {
+                    return Err(DeployError::Build(format!(
+                        "Artifact `{}` links back to `{}`, a directory it's inside of",
+                        entry_path.display(),
+                        target.display()
+                    )));
+                }
+                links.push(target);
+                let dir_name = format!("{}/", name);
+                collect_dir(entries, walk, links, &entry_path, &dir_name, depth + 1)?;
+                links.pop();
+                continue;
+            }
+        } else if file_type.is_dir() {
+            let dir_name = format!("{}/", name);
+            collect_dir(entries, walk, links, &entry_path, &dir_name, depth + 1)?;
+            continue;
+        }
+
+        entries.push(file_entry(&entry_path, name, walk.max_artifact_size)?);
+    }
+
+    if is_empty && !prefix.is_empty() {
+        entries.push(ArchiveEntry {
+            name: prefix.to_string(),
+            source: dir.to_path_buf(),
+            size: 0,
+            is_dir: true,
+        });
+    }
+
+    Ok(())
+}
+
+/// Where the symlink at `path` points, erroring when it's broken or points
+/// outside the walk's project directory.
+fn resolve_link(walk: &Walk, path: &Path) -> Result<PathBuf> {
+    let target = path.canonicalize().map_err(|err| {
+        DeployError::Build(format!(
+            "Error resolving artifact link `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    if walk
+        .project
+        .is_some_and(|project| !target.starts_with(project))
+    {
+        return Err(DeployError::Config(format!(
+            "Artifact `{}` resolves to `{}`, outside the project directory\n\
+             Use `--allow-external` to include it anyway",
+            path.display(),
+            target.display()
+        )));
+    }
+    Ok(target)
+}
+
+/// Entry for the file at `path`, erroring when it's bigger than `max` bytes.
+fn file_entry(path: &Path, name: String, max: Option<u64>) -> Result<ArchiveEntry> {
+    let size = path
+        .metadata()
+        .map_err(|err| {
+            DeployError::Build(format!(
+                "Error reading artifact size `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?
+        .len();
+    if let Some(max) = max.filter(|max| size > *max) {
+        return Err(DeployError::Build(format!(
+            "Artifact `{}` is {} bytes, over the `max_artifact_size` limit of {} bytes",
+            path.display(),
+            size,
+            max
+        )));
+    }
+
+    Ok(ArchiveEntry {
+        name,
+        source: path.to_path_buf(),
+        size,
+        is_dir: false,
+    })
+}
+
+/// Adds the file at `path` to the archive as `name`, streaming it through a
+/// fixed buffer and hashing it on the way.
+fn zip_file<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    manifest: &mut Manifest,
+    path: &Path,
+    name: &str,
+    options: SimpleFileOptions,
+) -> Result<()> {
+    zip.start_file(name, options).map_err(|err| {
+        DeployError::Build(format!(
+            "Error including artifact `{}`: {}",
+            path.display(),
+            err
+        ))
+    })?;
+
+    let (size, hasher) = File::open(path)
+        .and_then(|mut file| copy_hashed(&mut file, zip))
+        .map_err(|err| {
+            DeployError::Build(format!(
+                "Error copying artifact content `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?;
+
+    manifest.add(name, size, hasher);
+    Ok(())
+}
+
+/// Copies `reader` into `writer` through a [`COPY_BUFFER_SIZE`] buffer,
+/// returning the number of bytes copied and their hash.
+pub fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(u64, Sha256)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    let mut size = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok((size, hasher)),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        size += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A project with a file at the root, a nested site with an empty
+    /// directory, and a build output under `dist/`.
+    fn tree() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("env.production"), "KEY=1").unwrap();
+        fs::create_dir_all(root.join("site/css")).unwrap();
+        fs::create_dir_all(root.join("site/logs")).unwrap();
+        fs::write(root.join("site/index.html"), "<html></html>").unwrap();
+        fs::write(root.join("site/css/site.css"), "body {}").unwrap();
+        fs::create_dir_all(root.join("build/dist/js")).unwrap();
+        fs::write(root.join("build/dist/js/app.js"), "run()").unwrap();
+        dir
+    }
+
+    fn artifacts(dir: &TempDir, specs: Value) -> Vec<Artifact> {
+        serde_json::from_value(rooted(&dir.path().display().to_string(), specs)).unwrap()
+    }
+
+    /// Prefixes every artifact `src` in `specs` with `root`.
+    fn rooted(root: &str, specs: Value) -> Value {
+        specs
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|spec| match spec {
+                Value::String(src) => json!(format!("{}/{}", root, src)),
+                spec => {
+                    let mut spec = spec.clone();
+                    spec["src"] = json!(format!("{}/{}", root, spec["src"].as_str().unwrap()));
+                    spec
+                }
+            })
+            .collect()
+    }
+
+    fn options() -> ArchiveOptions {
+        ArchiveOptions {
+            compression: Compression::Deflated,
+            compression_level: None,
+            store_extensions: Vec::new(),
+            max_artifact_size: None,
+            max_archive_size: None,
+            max_depth: None,
+            allow_external: false,
+            reproducible: false,
+        }
+    }
+
+    /// Builds the archive into `dir`, returning its summary and sorted entry
+    /// names.
+    fn build(
+        dir: &TempDir,
+        specs: Value,
+        opts: &ArchiveOptions,
+    ) -> Result<(ArchiveSummary, Vec<String>)> {
+        let out = dir.path().join("out.zip");
+        let summary = build_archive(&artifacts(dir, specs), &out, opts)?;
+        Ok((summary, entry_names(&out)))
+    }
+
+    fn entry_names(path: &Path) -> Vec<String> {
+        let archive = ZipArchive::new(File::open(path).unwrap()).unwrap();
+        let mut names: Vec<String> = archive.file_names().map(str::to_string).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn streamed_content_and_hashes_match_the_sources() {
+        let dir = tree();
+        // Several copy buffers' worth, not a multiple of the buffer size.
+        let bundle: Vec<u8> = (0..COPY_BUFFER_SIZE * 3 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(dir.path().join("site/bundle.js"), &bundle).unwrap();
+        fs::write(dir.path().join("site/logo.png"), &bundle[..1000]).unwrap();
+        let opts = ArchiveOptions {
+            store_extensions: vec!["png".to_string()],
+            ..options()
+        };
+
+        let out = dir.path().join("out.zip");
+        let summary = build_archive(&artifacts(&dir, json!(["site"])), &out, &opts).unwrap();
+
+        let hashes = summary.manifest.hashes();
+        let mut archive = ZipArchive::new(File::open(&out).unwrap()).unwrap();
+        for name in ["bundle.js", "logo.png", "index.html", "css/site.css"] {
+            let source = fs::read(dir.path().join("site").join(name)).unwrap();
+            let mut content = Vec::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_end(&mut content)
+                .unwrap();
+            assert_eq!(content, source, "{}", name);
+            let sha256: String = Sha256::digest(&source)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            assert_eq!(hashes[name], sha256, "{}", name);
+        }
+        assert_eq!(
+            archive.by_name("logo.png").unwrap().compression(),
+            CompressionMethod::Stored
+        );
+        assert_eq!(
+            summary.manifest.total_size(),
+            bundle.len() as u64 + 1000 + 13 + 7
+        );
+    }
+
+    #[test]
+    fn single_file_uses_its_file_name() {
+        let dir = tree();
+        let (summary, names) = build(&dir, json!(["env.production"]), &options()).unwrap();
+        assert_eq!(names, ["env.production"]);
+        assert_eq!(summary.manifest.len(), 1);
+        assert_eq!(summary.manifest.total_size(), 5);
+    }
+
+    #[test]
+    fn single_file_renamed_by_dest() {
+        let dir = tree();
+        let specs = json!([{ "src": "env.production", "dest": ".env" }]);
+        let (_, names) = build(&dir, specs, &options()).unwrap();
+        assert_eq!(names, [".env"]);
+    }
+
+    #[test]
+    fn directory_contents_go_to_the_root_recursively() {
+        let dir = tree();
+        let (summary, names) = build(&dir, json!(["site"]), &options()).unwrap();
+        assert_eq!(names, ["css/site.css", "index.html", "logs/"]);
+        // The empty directory is an entry, but not a file in the manifest.
+        assert_eq!(summary.manifest.len(), 2);
+    }
+
+    #[test]
+    fn directory_contents_go_under_dest() {
+        let dir = tree();
+        let specs = json!([{ "src": "site", "dest": "public/" }]);
+        let (_, names) = build(&dir, specs, &options()).unwrap();
+        assert_eq!(
+            names,
+            ["public/css/site.css", "public/index.html", "public/logs/"]
+        );
+    }
+
+    #[test]
+    fn strip_prefix_moves_entries_up() {
+        let dir = tree();
+        let specs = json!([{ "src": "build", "strip_prefix": "dist", "dest": "app" }]);
+        let (_, names) = build(&dir, specs, &options()).unwrap();
+        assert_eq!(names, ["app/js/app.js"]);
+    }
+
+    #[test]
+    fn strip_prefix_not_matching_errors() {
+        let dir = tree();
+        let specs = json!([{ "src": "site", "strip_prefix": "dist" }]);
+        let err = build(&dir, specs, &options()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("isn't under its `strip_prefix` `dist`"));
+    }
+
+    #[test]
+    fn strip_prefix_on_a_file_errors() {
+        let dir = tree();
+        let specs = json!([{ "src": "env.production", "strip_prefix": "x" }]);
+        let err = build(&dir, specs, &options()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only applies to directory artifacts"));
+    }
+
+    #[test]
+    fn artifacts_combine_in_one_archive() {
+        let dir = tree();
+        let specs = json!(["site", { "src": "env.production", "dest": ".env" }]);
+        let (summary, names) = build(&dir, specs, &options()).unwrap();
+        assert_eq!(names, [".env", "css/site.css", "index.html", "logs/"]);
+        assert_eq!(
+            summary.size,
+            dir.path().join("out.zip").metadata().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn file_over_max_artifact_size_is_rejected() {
+        let dir = tree();
+        let opts = ArchiveOptions {
+            max_artifact_size: Some(6),
+            ..options()
+        };
+        // `index.html` is 13 bytes, the other files fit.
+        let err = build(&dir, json!(["site"]), &opts).unwrap_err();
+        assert!(err.to_string().contains("index.html"));
+        assert!(err
+            .to_string()
+            .contains("over the `max_artifact_size` limit of 6 bytes"));
+
+        assert!(build(&dir, json!(["env.production"]), &opts).is_ok());
+    }
+
+    #[test]
+    fn archive_over_max_archive_size_is_rejected() {
+        let dir = tree();
+        let opts = ArchiveOptions {
+            max_archive_size: Some(10),
+            ..options()
+        };
+        let err = build(&dir, json!(["site"]), &opts).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("over the `max_archive_size` limit"));
+    }
+
+    #[test]
+    fn symlink_outside_the_project_is_rejected() {
+        let dir = tree();
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("passwd"), "root:x:0:0").unwrap();
+        std::os::unix::fs::symlink(
+            outside.path().join("passwd"),
+            dir.path().join("site/passwd"),
+        )
+        .unwrap();
+
+        // The tests run from the crate's directory, which neither is under.
+        let err = build(&dir, json!(["site"]), &options()).unwrap_err();
+        assert!(matches!(err, DeployError::Config(_)));
+        assert!(err.to_string().contains("outside the project directory"));
+        assert!(err.to_string().contains("--allow-external"));
+
+        let opts = ArchiveOptions {
+            allow_external: true,
+            ..options()
+        };
+        let (_, names) = build(&dir, json!(["site"]), &opts).unwrap();
+        assert!(names.contains(&"passwd".to_string()));
+    }
+
+    #[test]
+    fn symlinked_directories_are_followed_until_they_loop() {
+        let dir = tree();
+        let opts = ArchiveOptions {
+            allow_external: true,
+            ..options()
+        };
+        std::os::unix::fs::symlink("css", dir.path().join("site/styles")).unwrap();
+        let (_, names) = build(&dir, json!(["site"]), &opts).unwrap();
+        assert!(names.contains(&"styles/site.css".to_string()));
+
+        std::os::unix::fs::symlink("..", dir.path().join("site/css/up")).unwrap();
+        let err = build(&dir, json!(["site"]), &opts).unwrap_err();
+        assert!(err.to_string().contains("links back to"));
+    }
+
+    #[test]
+    fn delta_archive_keeps_changed_files_and_directories() {
+        let dir = tree();
+        let full = dir.path().join("out.zip");
+        build(&dir, json!(["site"]), &options()).unwrap();
+
+        let delta = dir.path().join("delta.zip");
+        delta_archive(&full, &delta, &HashSet::from(["index.html"])).unwrap();
+        assert_eq!(entry_names(&delta), ["index.html", "logs/"]);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::cleanup::TempDir;
+use crate::cli::Cli;
+use crate::config::{App, Host, Platform};
+use crate::error::{DeployError, Result};
+use crate::manifest::Manifest;
+use crate::remote::{powershell, ps_quote, unwrap_command_stderr, Remote};
+use crate::rollback;
+use crate::signing;
+use crate::ssh::SshOverrides;
+use crate::{
+    close_session, open_session, output, prepare_artifacts, with_home_expanded, zip_artifacts,
+};
+
+/// How the files under `host_path` differ from the archive a deploy would
+/// extract there.
+#[derive(Debug, Default)]
+struct Drift {
+    /// In the archive, with different content on the host.
+    changed: Vec<String>,
+    /// On the host, but not in the archive.
+    extra: Vec<String>,
+    /// In the archive, but not on the host.
+    missing: Vec<String>,
+}
+
+impl Drift {
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.extra.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Builds and zips the app as a deploy would, then compares the archive's
+/// files with those under `host_path` on each of `hosts`, by SHA-256.
+/// Nothing is uploaded. Errors when any host has drifted.
+pub async fn audit(cli: &Cli, app: &App, hosts: &[Host]) -> Result<()> {
+    let archive_dir = TempDir::new("archive").map_err(|err| {
+        DeployError::Build(format!("Error creating the archive's directory: {}", err))
+    })?;
+    let archive_app = app.with_archive_dir(archive_dir.path());
+    let app = &archive_app;
+    let prepared = prepare_artifacts(cli, app).await?;
+    let app = &prepared.app;
+    zip_artifacts(app, cli)?;
+
+    let manifest_path = Manifest::path_for(app.archive_path());
+    let manifest = Manifest::load(&manifest_path)
+        .ok_or_else(|| DeployError::Build(format!("Error reading manifest `{}`", manifest_path)))?;
+    let expected = manifest.hashes();
+    let ignored = ignored_files(app);
+
+    let mut drifted = Vec::new();
+    for host in hosts {
+        let ssh = SshOverrides::prepare(host)?;
+        let session = open_session(host, ssh.as_ref()).await?;
+        let result = remote_hashes(&Remote::new(session.as_ref()), app, host.platform()).await;
+        close_session(session).await;
+        let (host_path, remote) = result?;
+
+        let drift = compare(&expected, &remote, &ignored);
+        if drift.is_empty() {
+            output::success(format!(
+                "`{}` on `{}` matches the artifacts",
+                host_path,
+                host.name()
+            ));
+            continue;
+        }
+
+        output::failure(format!(
+            "`{}` on `{}` has drifted from the artifacts:",
+            host_path,
+            host.name()
+        ));
+        for (label, paths) in [
+            ("changed on host", &drift.changed),
+            ("only on host", &drift.extra),
+            ("missing on host", &drift.missing),
+        ] {
+            for path in paths {
+                println!("  {}: {}", label, path);
+            }
+        }
+        drifted.push(host.name());
+    }
+
+    if drifted.is_empty() {
+        return Ok(());
+    }
+    Err(DeployError::Other(format!(
+        "Found drift on {}",
+        drifted.join(", ")
+    )))
+}
+
+/// SHA-256 of every file under `host_path`, by path relative to it with `/`
+/// separators, along with `host_path` with `~` expanded.
+async fn remote_hashes(
+    session: &Remote<'_>,
+    app: &App,
+    platform: Platform,
+) -> Result<(String, HashMap<String, String>)> {
+    let expanded_app;
+    let app = match with_home_expanded(session, app, platform).await? {
+        Some(app) => {
+            expanded_app = app;
+            &expanded_app
+        }
+        None => app,
+    };
+
+    let mut list = match platform {
+        Platform::Unix => {
+            let mut command = session.command("cd");
+            command
+                .arg(app.host_path())
+                .raw_arg("&&")
+                .args(["find", ".", "-type", "f", "-exec", "sha256sum", "{}", "+"]);
+            command
+        }
+        Platform::Windows => session.raw_command(powershell(&format!(
+            "Set-Location -LiteralPath {}; Get-ChildItem -Recurse -File -Force | \
+             ForEach-Object {{ (Get-FileHash -Algorithm SHA256 -LiteralPath $_.FullName).Hash.ToLower() + '  ' + (Resolve-Path -Relative -LiteralPath $_.FullName) }}",
+            ps_quote(app.host_path())
+        ))),
+    };
+    let out = unwrap_command_stderr(&mut list)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `find` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error listing the files of `{}`:\n{}",
+                app.host_path(),
+                err
+            ))
+        })?;
+
+    let hashes = out
+        .stdout
+        .lines()
+        .filter_map(|line| line.trim_end_matches('\r').split_once("  "))
+        .map(|(hash, path)| {
+            let path = path.replace('\\', "/");
+            let path = path.strip_prefix("./").unwrap_or(&path).to_string();
+            (path, hash.to_string())
+        })
+        .collect();
+    Ok((app.host_path().clone(), hashes))
+}
+
+/// Files in `host_path` that deploys write next to the extracted archive, or
+/// that are host-specific by design, and so aren't drift.
+fn ignored_files(app: &App) -> BTreeSet<String> {
+    let file_name = |path: &str| {
+        Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    let archive = app.remote_archive_name().to_string();
+    let mut ignored = BTreeSet::from([
+        format!("{}.partial", archive),
+        Manifest::path_for(&archive),
+        signing::signature_path(&archive),
+        rollback::BACKUP_NAME.to_string(),
+        file_name(&app.compose_images_path()),
+        app.deploy_marker().to_string(),
+        app.deploy_failed_marker().to_string(),
+        archive,
+    ]);
+    ignored.extend(app.sticky_files().iter().cloned());
+    ignored
+}
+
+fn compare(
+    expected: &HashMap<&str, &str>,
+    remote: &HashMap<String, String>,
+    ignored: &BTreeSet<String>,
+) -> Drift {
+    let mut drift = Drift::default();
+    for (path, hash) in expected {
+        match remote.get(*path) {
+            Some(remote_hash) if remote_hash != hash => drift.changed.push(path.to_string()),
+            Some(_) => {}
+            None => drift.missing.push(path.to_string()),
+        }
+    }
+    drift.extra = remote
+        .keys()
+        .filter(|path| !expected.contains_key(path.as_str()) && !ignored.contains(*path))
+        .cloned()
+        .collect();
+
+    drift.changed.sort();
+    drift.extra.sort();
+    drift.missing.sort();
+    drift
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+use crate::error::{DeployError, Result};
+
+/// Hashes of every file under the app's `when_changed` paths, recorded next
+/// to the archive as `<archive_name>.changes.json` after each successful
+/// deploy.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChangeState {
+    files: BTreeMap<String, String>,
+}
+
+impl ChangeState {
+    /// Local state path for the archive at `archive_name`.
+    pub fn path_for(archive_name: &str) -> String {
+        format!("{}.changes.json", archive_name)
+    }
+
+    /// Hashes the files under `paths` as they are now. Paths that don't exist
+    /// are left out, so deleting one counts as a change.
+    pub fn current(paths: &[String]) -> Result<Self> {
+        let mut state = Self::default();
+        for path in paths {
+            state.add(Path::new(path))?;
+        }
+        Ok(state)
+    }
+
+    /// State of the last successful deploy, `None` when there's none or it
+    /// can't be read, so the next deploy goes ahead.
+    pub fn load(path: &str) -> Option<Self> {
+        let content = fs::read(path).ok()?;
+        match serde_json::from_slice(&content) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                debug!(path, %err, "ignoring unreadable change state");
+                None
+            }
+        }
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let content = serde_json::to_vec_pretty(self).map_err(|err| {
+            DeployError::Other(format!("Error serializing change state: {}", err))
+        })?;
+        fs::write(path, content)
+            .map_err(|err| DeployError::Other(format!("Error writing `{}`: {}", path, err)))
+    }
+
+    fn add(&mut self, path: &Path) -> Result<()> {
+        if path.is_dir() {
+            let entries = path.read_dir().map_err(|err| {
+                DeployError::Other(format!(
+                    "Error reading `when_changed` directory `{}`: {}",
+                    path.display(),
+                    err
+                ))
+            })?;
+            for entry in entries {
+                let entry = entry.map_err(|err| {
+                    DeployError::Other(format!(
+                        "Error reading entry of `when_changed` directory `{}`: {}",
+                        path.display(),
+                        err
+                    ))
+                })?;
+                self.add(&entry.path())?;
+            }
+        } else if path.is_file() {
+            let content = fs::read(path).map_err(|err| {
+                DeployError::Other(format!("Error reading `{}`: {}", path.display(), err))
+            })?;
+            let sha256 = Sha256::digest(&content)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            self.files.insert(path.display().to_string(), sha256);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::IsTerminal;
+
+use crate::config::{App, Host, Platform};
+use crate::error::{DeployError, Result};
+use crate::remote::{powershell, ps_quote, unwrap_command_stderr, Remote};
+use crate::ssh::SshOverrides;
+use crate::{close_session, open_session, output, with_home_expanded};
+use dialoguer::Confirm;
+
+/// Fewest path components `host_path` needs before `clean` empties it, so a
+/// typo like `/` or `/home` can't wipe a whole system directory.
+const MIN_DEPTH: usize = 2;
+
+/// Removes everything under the app's `host_path` on each of `hosts`, keeping
+/// the directory itself. Asks for confirmation first unless `yes` is set.
+pub async fn clean(app: &App, hosts: &[Host], yes: bool) -> Result<()> {
+    check_depth(app.host_path())?;
+
+    let names: Vec<&str> = hosts.iter().map(Host::name).collect();
+    if !yes {
+        let interactive = std::io::stdout().is_terminal() && std::io::stdin().is_terminal();
+        if !interactive {
+            return Err(DeployError::Config(
+                "`clean` needs confirmation, pass `--yes` to run it non-interactively".to_string(),
+            ));
+        }
+        let proceed = Confirm::new()
+            .with_prompt(format!(
+                "Remove everything under `{}` on {}?",
+                app.host_path(),
+                names.join(", ")
+            ))
+            .default(false)
+            .interact()
+            .map_err(|err| DeployError::Other(format!("Error reading confirmation: {}", err)))?;
+        if !proceed {
+            return Err(DeployError::Other("Clean aborted".to_string()));
+        }
+    }
+
+    for host in hosts {
+        let ssh = SshOverrides::prepare(host)?;
+        let session = open_session(host, ssh.as_ref()).await?;
+        let result = clean_host(&Remote::new(session.as_ref()), app, host.platform()).await;
+        close_session(session).await;
+        let host_path = result?;
+
+        output::success(format!("Cleaned `{}` on `{}`", host_path, host.name()));
+    }
+
+    Ok(())
+}
+
+/// Empties `host_path` on the host, returning it with `~` expanded.
+async fn clean_host(session: &Remote<'_>, app: &App, platform: Platform) -> Result<String> {
+    let expanded_app;
+    let app = match with_home_expanded(session, app, platform).await? {
+        Some(app) => {
+            check_depth(app.host_path())?;
+            expanded_app = app;
+            &expanded_app
+        }
+        None => app,
+    };
+
+    let mut remove = match platform {
+        Platform::Unix => {
+            let mut command = session.command("find");
+            command.args([app.host_path(), "-mindepth", "1", "-delete"]);
+            command
+        }
+        Platform::Windows => session.raw_command(powershell(&format!(
+            "Get-ChildItem -Force -LiteralPath {} | Remove-Item -Recurse -Force",
+            ps_quote(app.host_path())
+        ))),
+    };
+    unwrap_command_stderr(&mut remove)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `find` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error removing the contents of `{}`:\n{}",
+                app.host_path(),
+                err
+            ))
+        })?;
+    Ok(app.host_path().clone())
+}
+
+/// Refuses `host_path`s with fewer than [`MIN_DEPTH`] components, not
+/// counting a Windows drive, or with `..` in them.
+fn check_depth(host_path: &str) -> Result<()> {
+    let components: Vec<&str> = host_path
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .filter(|component| !component.ends_with(':'))
+        .collect();
+    if components.len() < MIN_DEPTH || components.contains(&"..") {
+        return Err(DeployError::Config(format!(
+            "Refusing to clean `{}`: `host_path` must be at least {} directories deep",
+            host_path, MIN_DEPTH
+        )));
+    }
+    Ok(())
+}
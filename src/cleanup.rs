@@ -0,0 +1,106 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// Intermediate local file that's removed once dropped, so it doesn't outlive
+/// the deploy whether it succeeds, fails, panics or is interrupted with
+/// Ctrl-C during the remote phases.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Takes ownership of `path`, which may not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> TempFile {
+        TempFile { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Intermediate local directory that's removed with its content once
+/// dropped, like [`TempFile`].
+#[derive(Debug)]
+pub struct TempDir {
+    dir: Option<tempfile::TempDir>,
+}
+
+impl TempDir {
+    /// Creates a new directory under the system's temporary directory, only
+    /// accessible to the current user. Its name starts with `name` and ends
+    /// with a random suffix, so every deploy gets its own and an existing
+    /// directory is never reused.
+    pub fn new(name: &str) -> std::io::Result<TempDir> {
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("ec2-deploy-{}-", name))
+            .permissions(fs::Permissions::from_mode(0o700))
+            .tempdir()?;
+        Ok(TempDir { dir: Some(dir) })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir
+            .as_ref()
+            .expect("temporary directory used after drop")
+            .path()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let Some(dir) = self.dir.take() else {
+            return;
+        };
+        let path = dir.path().to_path_buf();
+        match dir.close() {
+            Ok(()) => debug!(path = %path.display(), "removed temporary directory"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => debug!(path = %path.display(), %err, "temporary directory not removed"),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => debug!(path = %self.path.display(), "removed temporary file"),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => debug!(path = %self.path.display(), %err, "temporary file not removed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_deploys_get_their_own_directory() {
+        let first = TempDir::new("archive").unwrap();
+        let second = TempDir::new("archive").unwrap();
+
+        assert_ne!(first.path(), second.path());
+        for dir in [&first, &second] {
+            let name = dir.path().file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with("ec2-deploy-archive-"));
+            let mode = fs::metadata(dir.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+
+    #[test]
+    fn directory_is_removed_with_its_content_once_dropped() {
+        let dir = TempDir::new("archive").unwrap();
+        let path = dir.path().to_path_buf();
+        fs::write(path.join("app.zip"), "zip").unwrap();
+
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
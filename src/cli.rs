@@ -0,0 +1,259 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::fmt;
+use std::path::PathBuf;
+
+const EXIT_CODES: &str = "\
+Exit status:
+  0  Deploy succeeded
+  1  Generic failure
+  2  Configuration error
+  3  Connection error
+  4  Build error
+  5  Remote command or restart error";
+
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = EXIT_CODES)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+
+    /// Run the build command through `sh -c` instead of executing it directly
+    #[arg(long, global = true)]
+    pub shell_build: bool,
+
+    /// Allow artifacts with absolute paths or paths outside the project directory, and
+    /// symlinks in artifact directories pointing outside it
+    #[arg(long, global = true)]
+    pub allow_external: bool,
+
+    /// Hosts config file, or a directory whose `*.json` files are all loaded.
+    /// Defaults to `~/ec2-deploy/hosts.json`. Use `-` to read it from stdin
+    #[arg(long, global = true, env = "EC2_DEPLOY_HOSTS", value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Deploy file to use instead of `deploy.json` in the current directory.
+    /// Artifact, build output and entrypoint paths, as well as the build
+    /// command, are resolved relative to the directory containing this file.
+    /// Use `-` to read it from stdin
+    #[arg(long, global = true, value_name = "PATH")]
+    pub deploy_file: Option<PathBuf>,
+
+    /// Deploy the app of this deploy file, then of the next one, to the
+    /// selected hosts, reporting how each went. Can be repeated
+    #[arg(
+        long = "app",
+        value_name = "PATH",
+        conflicts_with_all = ["deploy_file", "target_path"]
+    )]
+    pub apps: Vec<PathBuf>,
+
+    /// Name of the host to deploy to, as set in `hosts.json`. A glob such as
+    /// `prod-*` selects every matching host
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+
+    /// Deploy to this host instead of the ones in `hosts.json`, given as
+    /// `ssh://user@host[:port]`. Needs `--key`
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        requires = "key",
+        conflicts_with_all = ["host", "all", "select_host"]
+    )]
+    pub host_url: Option<String>,
+
+    /// Private key for `--host-url`
+    #[arg(long, global = true, value_name = "PATH", requires = "host_url")]
+    pub key: Option<String>,
+
+    /// Profile from the deploy file's `profiles` whose fields replace the
+    /// base ones, e.g. `staging` or `prod`
+    #[arg(long, global = true, env = "EC2_DEPLOY_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Deploy to every host with this role in its `roles`. `--host` narrows
+    /// the selection further
+    #[arg(long, global = true, conflicts_with = "all")]
+    pub role: Option<String>,
+
+    /// Deploy to every configured host
+    #[arg(long, global = true, conflicts_with_all = ["host", "select_host"])]
+    pub all: bool,
+
+    /// Pick the host from an interactive list
+    #[arg(long, global = true, conflicts_with = "host")]
+    pub select_host: bool,
+
+    /// Log per-command details for each deploy phase. `RUST_LOG` takes
+    /// precedence when set
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+
+    /// List the files about to be archived with their total size, and ask
+    /// before continuing when running in a terminal. `--verbose` does the same
+    #[arg(long, global = true)]
+    pub interactive: bool,
+
+    /// Only zip and upload the given artifact, extracting it over the existing
+    /// files on the host. Can be repeated
+    #[arg(long = "only-artifact", global = true, value_name = "PATH")]
+    pub only_artifacts: Vec<String>,
+
+    /// Upload the app's single file artifact as is into `host_path` instead
+    /// of zipping it, skipping the unzip. Its mode is set from `raw_mode`
+    #[arg(long, global = true, visible_alias = "no-unzip")]
+    pub raw: bool,
+
+    /// Upload only the archive entries that are new or changed since the
+    /// last deploy to each host, and remove the ones that are gone. Hosts
+    /// without a recorded deploy get the full archive
+    #[arg(long, global = true, conflicts_with = "raw")]
+    pub only_upload_changed_entries: bool,
+
+    /// Upload the full archive even with `--only-upload-changed-entries`,
+    /// still recording it for the next incremental deploy
+    #[arg(long, global = true)]
+    pub full: bool,
+
+    /// Zip the artifacts with their entries sorted and fixed timestamps, so
+    /// identical files give a byte-identical archive
+    #[arg(long, global = true)]
+    pub reproducible: bool,
+
+    /// Deploy into this directory instead of the app's `host_path` for this
+    /// run, e.g. a scratch directory to try a deploy in. Must be absolute or
+    /// start with `~`
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        conflicts_with = "only_upload_changed_entries"
+    )]
+    pub target_path: Option<String>,
+
+    /// Throttle the archive upload to this many KB/s on average
+    #[arg(
+        long,
+        global = true,
+        value_name = "KB/s",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub limit_rate: Option<u64>,
+
+    /// Replace the app's `sticky_files` on the host even when they differ
+    /// from the local copy
+    #[arg(long, global = true)]
+    pub overwrite_config: bool,
+
+    /// Load `KEY=VALUE` pairs for `${VAR}` references in the config files.
+    /// Values override the environment, and later files earlier ones. Can be
+    /// repeated
+    #[arg(long = "env-file", global = true, value_name = "PATH")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Ignore every safety check that would stop or short-circuit a deploy:
+    /// sticky files are replaced without comparing them, the file list isn't
+    /// confirmed, unchanged `when_changed` paths don't skip the deploy and
+    /// compose builds run below `min_build_memory_mb`. Each override is
+    /// logged
+    #[arg(long, global = true)]
+    pub force: bool,
+
+    /// Run only this phase of the deploy, assuming the earlier ones already
+    /// ran. `upload` zips the artifacts as they are, without building, and
+    /// `extract` unzips the archive already on the host
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["raw", "only_upload_changed_entries"]
+    )]
+    pub step: Option<Step>,
+
+    /// Disable colored output. Colors are also off when `NO_COLOR` is set or
+    /// output isn't a terminal
+    #[arg(long, global = true)]
+    pub no_color: bool,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Deploy, then watch the artifact paths and redeploy whenever they change
+    Watch {
+        /// Milliseconds to wait for further changes before redeploying
+        #[arg(long, default_value_t = 500, value_name = "MS")]
+        debounce: u64,
+    },
+    /// Print the resolved deploy and host config as JSON without deploying
+    PrintConfig,
+    /// Check the config files, SSH keys, build command and host connectivity
+    Doctor,
+    /// Resolve the config, hosts and artifacts into a plan file for review,
+    /// without deploying
+    Plan {
+        /// File to write the plan to
+        #[arg(short, long, value_name = "PATH")]
+        output: PathBuf,
+    },
+    /// Remove everything under the app's `host_path` on the selected hosts,
+    /// after confirming
+    Clean {
+        /// Skip the confirmation prompt
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Build the archive and compare its files with those under `host_path`
+    /// on the selected hosts, reporting drift without deploying
+    Audit,
+    /// Print the latest deploys recorded in `~/ec2-deploy/history.jsonl`,
+    /// across projects. `--host` keeps those to matching hosts
+    History {
+        /// Only list deploys of the app with this name
+        #[arg(long)]
+        app: Option<String>,
+        /// Number of deploys to list
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print a JSON Schema for a config file, for editor validation and
+    /// completion
+    Schema {
+        #[arg(value_enum)]
+        file: SchemaFile,
+    },
+    /// Deploy exactly what a plan file describes, without reading any other
+    /// config
+    Apply {
+        /// Plan file written by `plan`
+        plan: PathBuf,
+    },
+}
+
+/// Config file a schema is printed for.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SchemaFile {
+    /// `deploy.json`
+    Deploy,
+    /// `hosts.json`
+    Hosts,
+}
+
+/// Deploy phase run on its own with `--step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Step {
+    /// Run the build command
+    Build,
+    /// Zip the artifacts and upload the archive to the hosts
+    Upload,
+    /// Extract the uploaded archive into `host_path`
+    Extract,
+    /// Restart the app with its strategies
+    Restart,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no step is skipped");
+        f.write_str(value.get_name())
+    }
+}
@@ -0,0 +1,1594 @@
+use openssh::KnownHosts;
+use openssh_sftp_client::SftpOptions;
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{from_slice, from_value, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Read};
+use std::num::{NonZeroU16, NonZeroUsize};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::warn;
+use zip::CompressionMethod;
+
+use crate::error::{DeployError, Result};
+use crate::ssm;
+use crate::upload;
+use crate::vars::Vars;
+
+/// Directory holding the user-level configuration files (`hosts.json`,
+/// `config.json`).
+pub fn config_dir() -> Result<PathBuf> {
+    let home = std::env::var("HOME")
+        .map_err(|_| DeployError::Config("`HOME` environment variable is not set".to_string()))?;
+    Ok(PathBuf::from(home).join("ec2-deploy"))
+}
+
+/// Path given on the command line to read a config file from stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Reads a config file, or stdin when `path` is `-`.
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    if path == Path::new(STDIN_PATH) {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content)?;
+        return Ok(content);
+    }
+    fs::read(path)
+}
+
+/// Parses a JSON config file, expanding `${VAR}` references in its strings.
+fn parse_value(content: &[u8], vars: &Vars) -> std::result::Result<Value, String> {
+    let mut value: Value = from_slice(content).map_err(|err| err.to_string())?;
+    vars.substitute(&mut value)?;
+    Ok(value)
+}
+
+/// Merges the deploy file `value`, read from `path`, over the one named by
+/// its `extends` field, recursively. Its top-level fields replace those of
+/// the base, whose path is relative to the extending file. `chain` holds the
+/// files extending this one, to catch cycles, and `origins` gets the file
+/// each field of the result came from. Relative paths in inherited fields,
+/// like artifacts, still resolve from the directory of the deploy file
+/// being loaded, which the deploy runs from.
+fn extend(
+    path: &Path,
+    value: Value,
+    vars: &Vars,
+    chain: &mut Vec<PathBuf>,
+    origins: &mut BTreeMap<String, String>,
+) -> std::result::Result<Value, String> {
+    let Value::Object(mut fields) = value else {
+        return Ok(value);
+    };
+    let base = match fields.remove("extends") {
+        Some(Value::String(base)) => base,
+        Some(_) => return Err("`extends` must be the path of a deploy file".to_string()),
+        None => {
+            if !chain.is_empty() {
+                for field in fields.keys() {
+                    origins.insert(field.clone(), path.display().to_string());
+                }
+            }
+            return Ok(Value::Object(fields));
+        }
+    };
+
+    chain.push(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    let base_path = path.parent().unwrap_or(Path::new("")).join(base);
+    let canonical = base_path
+        .canonicalize()
+        .map_err(|err| format!("Error opening `{}`: {}", base_path.display(), err))?;
+    if chain.contains(&canonical) {
+        let cycle: Vec<String> = chain
+            .iter()
+            .chain([&canonical])
+            .map(|file| file.display().to_string())
+            .collect();
+        return Err(format!("`extends` cycle: {}", cycle.join(" -> ")));
+    }
+    let content = fs::read(&base_path)
+        .map_err(|err| format!("Error opening `{}`: {}", base_path.display(), err))?;
+    let base_value = parse_value(&content, vars)
+        .map_err(|err| format!("Error parsing `{}`: {}", base_path.display(), err))?;
+    let Value::Object(mut merged) = extend(&base_path, base_value, vars, chain, origins)? else {
+        return Err(format!("`{}` isn't a JSON object", base_path.display()));
+    };
+    chain.pop();
+
+    for (field, value) in fields {
+        origins.insert(field.clone(), path.display().to_string());
+        merged.insert(field, value);
+    }
+    Ok(Value::Object(merged))
+}
+
+/// Replaces the top-level fields of `app` with those of its `profile`, and
+/// drops the `profiles` table either way. Returns the replaced fields.
+fn apply_profile(
+    app: &mut Value,
+    profile: Option<&str>,
+) -> std::result::Result<Vec<String>, String> {
+    let Some(app) = app.as_object_mut() else {
+        return Ok(Vec::new());
+    };
+    let profiles = app.remove("profiles");
+    let Some(name) = profile else {
+        return Ok(Vec::new());
+    };
+
+    let Some(Value::Object(profiles)) = profiles else {
+        return Err(format!(
+            "`--profile {}` given but no `profiles` are defined",
+            name
+        ));
+    };
+    let Some(Value::Object(overrides)) = profiles.get(name) else {
+        let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        return Err(format!(
+            "Unknown profile `{}`, expected one of: {}",
+            name,
+            known.join(", ")
+        ));
+    };
+    for (field, value) in overrides {
+        app.insert(field.clone(), value.clone());
+    }
+    Ok(overrides.keys().cloned().collect())
+}
+
+/// Loads the hosts from `path`. When `path` is a directory every `*.json`
+/// file in it is read and their host lists are concatenated.
+pub fn load_hosts(path: &Path, vars: &Vars) -> Result<Vec<Host>> {
+    let files = if path.is_dir() {
+        let entries = path.read_dir().map_err(|err| {
+            DeployError::Config(format!(
+                "Error reading hosts directory `{}`: {}",
+                path.display(),
+                err
+            ))
+        })?;
+
+        let mut files = Vec::new();
+        for e in entries {
+            let file = e
+                .map_err(|err| {
+                    DeployError::Config(format!("Error reading hosts directory entry: {}", err))
+                })?
+                .path();
+            if file.extension().is_some_and(|ext| ext == "json") {
+                files.push(file);
+            }
+        }
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut hosts: Vec<Host> = Vec::new();
+    let mut sources: Vec<&Path> = Vec::new();
+
+    for file in &files {
+        let content = read(file).map_err(|err| {
+            DeployError::Config(format!(
+                "Error opening hosts config file `{}`: {}",
+                file.display(),
+                err
+            ))
+        })?;
+        let error = |err: String| {
+            DeployError::Config(format!(
+                "Error parsing hosts config file `{}`: {}",
+                file.display(),
+                err
+            ))
+        };
+        let mut value = parse_value(&content, vars).map_err(error)?;
+        ssm::resolve(&mut value).map_err(error)?;
+        let file_hosts: Vec<Host> = from_value(value).map_err(|err| error(err.to_string()))?;
+
+        for host in file_hosts {
+            host.check().map_err(error)?;
+            if let Some(i) = hosts.iter().position(|h| h.name() == host.name()) {
+                return Err(DeployError::Config(format!(
+                    "Duplicate host `{}` found in `{}` and `{}`",
+                    host.name(),
+                    sources[i].display(),
+                    file.display()
+                )));
+            }
+            hosts.push(host);
+            sources.push(file);
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// User-wide defaults read from `~/ec2-deploy/config.json`.
+///
+/// Every field is optional. Settings are resolved with the following
+/// precedence, highest first:
+///
+/// 1. CLI flags
+/// 2. `deploy.json` / `hosts.json`
+/// 3. this global config file
+/// 4. built-in defaults
+#[derive(Debug, Default, Deserialize)]
+pub struct GlobalConfig {
+    known_hosts: Option<KnownHostsPolicy>,
+    retries: Option<u32>,
+    deploy_retries: Option<u32>,
+    keepalive: Option<u64>,
+    compression: Option<Compression>,
+    compression_level: Option<i64>,
+}
+
+impl GlobalConfig {
+    /// Loads the global config file, falling back to empty defaults when it
+    /// doesn't exist.
+    pub fn load() -> Result<GlobalConfig> {
+        let path = config_dir()?.join("config.json");
+        match read(&path) {
+            Ok(content) => from_slice(content.as_slice()).map_err(|err| {
+                DeployError::Config(format!("Error parsing `{}`: {}", path.display(), err))
+            }),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(GlobalConfig::default()),
+            Err(err) => Err(DeployError::Config(format!(
+                "Error opening `{}`: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+}
+
+/// How the remote host key is checked against `known_hosts`.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KnownHostsPolicy {
+    /// Refuse to connect to hosts not already in `known_hosts`.
+    Strict,
+    /// Add unknown hosts to `known_hosts`, refusing changed keys.
+    Add,
+    /// Accept any host key.
+    Accept,
+}
+
+impl From<KnownHostsPolicy> for KnownHosts {
+    fn from(policy: KnownHostsPolicy) -> KnownHosts {
+        match policy {
+            KnownHostsPolicy::Strict => KnownHosts::Strict,
+            KnownHostsPolicy::Add => KnownHosts::Add,
+            KnownHostsPolicy::Accept => KnownHosts::Accept,
+        }
+    }
+}
+
+/// Operating system of a host, which decides how remote paths are joined and
+/// which commands create directories and extract the archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    #[default]
+    Unix,
+    /// Windows OpenSSH server. The archive is extracted with PowerShell's
+    /// `Expand-Archive` and unix permissions are left alone.
+    Windows,
+}
+
+impl Platform {
+    /// Joins `name` onto `base` with the platform's separator.
+    pub fn join(self, base: &str, name: &str) -> String {
+        match self {
+            Platform::Unix => format!("{}/{}", base, name),
+            Platform::Windows => format!("{}\\{}", base.trim_end_matches(['/', '\\']), name),
+        }
+    }
+}
+
+/// How the host is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Ssh,
+    /// No SSH: `host_path` is a directory on this machine, files are copied
+    /// and commands run locally. For trying out the whole pipeline, or
+    /// deploying to the machine ec2-deploy runs on. Unix only.
+    Local,
+}
+
+/// How files are copied to the host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Transfer {
+    #[default]
+    Sftp,
+    /// `scp` and shell commands over the session, for hosts with the SFTP
+    /// subsystem disabled. Unix hosts only.
+    Scp,
+}
+
+/// What the archive is built from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// The files and directories listed in `artifacts`.
+    #[default]
+    Artifacts,
+    /// The deploy file's directory as committed at `git_ref`, via
+    /// `git archive`.
+    Git,
+}
+
+/// How `unzip` treats files that already exist in `host_path`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Overwrite {
+    /// Replace existing files.
+    #[default]
+    Always,
+    /// Keep existing files, only extracting new ones.
+    Never,
+    /// Replace existing files only when the archive's copy is newer.
+    Update,
+}
+
+/// What a deploy failing once the upload started leaves in `host_path`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// Leave the files as the failed deploy left them.
+    #[default]
+    Leave,
+    /// Restore `host_path` from a tarball of it taken before the upload and,
+    /// when the failure came at or after the restart, restart the app again.
+    /// Unix hosts only.
+    Rollback,
+}
+
+impl Overwrite {
+    /// Matching `unzip` flag.
+    pub fn unzip_flag(self) -> &'static str {
+        match self {
+            Overwrite::Always => "-o",
+            Overwrite::Never => "-n",
+            Overwrite::Update => "-uo",
+        }
+    }
+}
+
+/// Compression method used for the archive entries.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Stored,
+    Deflated,
+}
+
+impl Compression {
+    /// Levels the method accepts, or `None` when it takes no level.
+    pub fn levels(self) -> Option<RangeInclusive<i64>> {
+        match self {
+            Compression::Stored => None,
+            Compression::Deflated => Some(0..=9),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Stored => "stored",
+            Compression::Deflated => "deflated",
+        })
+    }
+}
+
+impl From<Compression> for CompressionMethod {
+    fn from(compression: Compression) -> CompressionMethod {
+        match compression {
+            Compression::Stored => CompressionMethod::Stored,
+            Compression::Deflated => CompressionMethod::Deflated,
+        }
+    }
+}
+
+/// Remote users, switched to with `sudo -u`, that steps run as instead of the
+/// connecting user.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct RunAs {
+    restart: Option<String>,
+    after_extract: Option<String>,
+}
+
+impl RunAs {
+    /// User for the restart strategy's commands.
+    pub fn restart(&self) -> Option<&str> {
+        self.restart.as_deref()
+    }
+    /// User for the `after_extract` hooks.
+    pub fn after_extract(&self) -> Option<&str> {
+        self.after_extract.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct App {
+    name: String,
+    host_path: String,
+    host_path_mode: Option<String>,
+    raw_mode: Option<String>,
+    /// Name of the zip the artifacts are packed into.
+    archive_name: Option<String>,
+    /// File name the archive is uploaded as on the host.
+    remote_archive_name: Option<String>,
+    /// Deprecated name of `archive_name`, moved over on load.
+    #[serde(default, skip_serializing)]
+    build_output_file: Option<String>,
+    #[serde(default)]
+    build_command: BuildCommand,
+    #[serde(default)]
+    shell: bool,
+    build_retries: Option<u32>,
+    #[serde(default)]
+    smoke_test: BuildCommand,
+    warmup_url: Option<String>,
+    warmup_requests: Option<u32>,
+    build_image: Option<String>,
+    build_output_from_stdout: Option<String>,
+    #[serde(default)]
+    source: Source,
+    git_ref: Option<String>,
+    #[serde(default)]
+    artifacts: Vec<Artifact>,
+    #[serde(default)]
+    artifacts_command: BuildCommand,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_schema")]
+    entrypoint: Vec<String>,
+    #[serde(default)]
+    run_entrypoint: bool,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_strategies")]
+    strategy: Vec<Strategy>,
+    service_name: Option<String>,
+    reload_command: Option<String>,
+    min_build_memory_mb: Option<u64>,
+    #[serde(default)]
+    compose_build_local: bool,
+    compression: Option<Compression>,
+    compression_level: Option<i64>,
+    #[serde(default)]
+    store_extensions: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_schema")]
+    after_extract: Vec<String>,
+    max_artifact_size: Option<u64>,
+    max_archive_size: Option<u64>,
+    max_depth: Option<usize>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_schema")]
+    sticky_files: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    #[schemars(schema_with = "one_or_many_schema")]
+    when_changed: Vec<String>,
+    #[serde(default)]
+    upload_manifest: bool,
+    #[serde(default)]
+    verify_file_count: bool,
+    deploy_marker: Option<String>,
+    deploy_failed_marker: Option<String>,
+    sign_key: Option<String>,
+    sign_public_key: Option<String>,
+    #[serde(default)]
+    overwrite: Overwrite,
+    #[serde(default)]
+    run_as: RunAs,
+    #[serde(default)]
+    on_failure: OnFailure,
+    #[serde(skip)]
+    origins: BTreeMap<String, String>,
+    #[serde(skip)]
+    archive_path: Option<String>,
+}
+
+impl App {
+    /// Reads and parses a deploy file, merged over the files it `extends`,
+    /// with the fields of `profile` from its `profiles` table replacing the
+    /// base ones when given.
+    pub fn load(path: &Path, vars: &Vars, profile: Option<&str>) -> Result<App> {
+        let content = read(path).map_err(|err| {
+            DeployError::Config(format!("Error opening `{}`: {}", path.display(), err))
+        })?;
+        let mut origins = BTreeMap::new();
+        let mut app: App = parse_value(&content, vars)
+            .and_then(|value| {
+                let mut value = extend(path, value, vars, &mut Vec::new(), &mut origins)?;
+                let overridden = apply_profile(&mut value, profile)?;
+                origins.remove("profiles");
+                if !origins.is_empty() {
+                    for field in overridden {
+                        origins.insert(
+                            field,
+                            format!("{} (profile `{}`)", path.display(), profile.unwrap_or("")),
+                        );
+                    }
+                }
+                from_value(value).map_err(|err| err.to_string())
+            })
+            .map_err(|err| {
+                DeployError::Config(format!("Error parsing `{}`: {}", path.display(), err))
+            })?;
+        app.origins = origins;
+        app.resolve_archive_name(path)?;
+        Ok(app)
+    }
+
+    /// File each field came from, when the deploy file `extends` another.
+    pub fn origins(&self) -> &BTreeMap<String, String> {
+        &self.origins
+    }
+
+    /// Moves the deprecated `build_output_file` over to `archive_name`,
+    /// warning about it, and errors when neither is set. `path` is the file
+    /// the app was read from.
+    pub fn resolve_archive_name(&mut self, path: &Path) -> Result<()> {
+        match (self.build_output_file.take(), &self.archive_name) {
+            (Some(old), Some(name)) if &old != name => Err(DeployError::Config(format!(
+                "`build_output_file` and `archive_name` differ in `{}`, remove the deprecated `build_output_file`",
+                path.display()
+            ))),
+            (Some(old), _) => {
+                warn!(
+                    "`build_output_file` in `{}` is deprecated, rename it to `archive_name`",
+                    path.display()
+                );
+                self.archive_name = Some(old);
+                Ok(())
+            }
+            (None, Some(_)) => Ok(()),
+            (None, None) => Err(DeployError::Config(format!(
+                "`{}` is missing `archive_name`, the name of the zip to pack the artifacts into",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Local path the archive, its manifest and signature are written to:
+    /// in the deploy's own temporary directory once [`App::with_archive_dir`]
+    /// applies, so concurrent deploys from one directory don't clobber each
+    /// other, and `archive_name` before.
+    pub fn archive_path(&self) -> &str {
+        self.archive_path
+            .as_deref()
+            .unwrap_or_else(|| self.archive_name())
+    }
+    /// Copy of the app writing its archive into `dir`, under the file name of
+    /// `archive_name`.
+    pub fn with_archive_dir(&self, dir: &Path) -> App {
+        let file_name = Path::new(self.archive_name())
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.archive_name().to_string());
+        App {
+            archive_path: Some(dir.join(file_name).display().to_string()),
+            ..self.clone()
+        }
+    }
+    /// File name the archive is uploaded as into `host_path`,
+    /// `remote_archive_name` or else the file name of `archive_name`.
+    pub fn remote_archive_name(&self) -> &str {
+        if let Some(name) = &self.remote_archive_name {
+            return name;
+        }
+        Path::new(self.archive_name())
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_else(|| self.archive_name())
+    }
+    /// Name of the zip the artifacts are packed into, relative to the deploy
+    /// file's directory. The archive is uploaded under its file name by
+    /// default, and the local state of past deploys is kept next to it.
+    pub fn archive_name(&self) -> &str {
+        self.archive_name
+            .as_deref()
+            .expect("archive_name read before resolve_archive_name")
+    }
+    pub fn host_path(&self) -> &String {
+        &self.host_path
+    }
+    /// Permission bits `host_path` is given after it's created, from an
+    /// octal string such as `"755"`. Errors when it isn't a valid mode.
+    pub fn host_path_mode(&self) -> Result<Option<u16>> {
+        octal_mode("host_path_mode", self.host_path_mode.as_deref())
+    }
+    /// Permission bits of the file uploaded with `--raw`, in the same form
+    /// as `host_path_mode`.
+    pub fn raw_mode(&self) -> Result<Option<u16>> {
+        octal_mode("raw_mode", self.raw_mode.as_deref())
+    }
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+    pub fn artifacts(&self) -> &Vec<Artifact> {
+        &self.artifacts
+    }
+    pub fn source(&self) -> Source {
+        self.source
+    }
+    /// Commit, branch or tag archived with the git source.
+    pub fn git_ref(&self) -> &str {
+        self.git_ref.as_deref().unwrap_or("HEAD")
+    }
+    /// Entrypoint scripts, in the order they're processed.
+    pub fn entrypoints(&self) -> &Vec<String> {
+        &self.entrypoint
+    }
+    /// Whether the entrypoint strategy runs each entrypoint after making it
+    /// executable.
+    pub fn run_entrypoint(&self) -> bool {
+        self.run_entrypoint
+    }
+    /// systemd unit restarted by the `systemd` strategy.
+    pub fn service_name(&self) -> &Option<String> {
+        &self.service_name
+    }
+
+    /// Memory in MiB the host must have available before `docker-compose
+    /// build` runs, so a build that would be OOM-killed isn't started.
+    pub fn min_build_memory_mb(&self) -> Option<u64> {
+        self.min_build_memory_mb
+    }
+
+    /// Whether the compose images are built locally and shipped with the
+    /// archive, for `docker load` on the host, instead of built there.
+    pub fn compose_build_local(&self) -> bool {
+        self.compose_build_local && self.strategies().contains(&Strategy::Compose)
+    }
+    /// Local path of the `docker save` tarball for `compose_build_local`.
+    pub fn compose_images_path(&self) -> String {
+        format!("{}.images.tar", self.archive_path())
+    }
+
+    /// Command the `static` strategy runs after extraction.
+    pub fn reload_command(&self) -> &str {
+        self.reload_command
+            .as_deref()
+            .unwrap_or("sudo nginx -s reload")
+    }
+
+    /// Restart strategies run in order, as resolved by
+    /// [`App::resolve_strategy`].
+    pub fn strategies(&self) -> &[Strategy] {
+        match self.strategy.as_slice() {
+            [] => &[Strategy::Compose],
+            strategies => strategies,
+        }
+    }
+
+    /// Resolves the restart strategies, checking that the fields each needs
+    /// are set and that no other strategy's fields are. `strategy` may list
+    /// several to run in order. Without an explicit `strategy`, `entrypoint`
+    /// implies the entrypoint strategy, `service_name` the systemd one,
+    /// `reload_command` the static one and compose is used otherwise.
+    pub fn resolve_strategy(&mut self) -> Result<Vec<Strategy>> {
+        let mut implied = Vec::new();
+        if !self.entrypoint.is_empty() {
+            implied.push(Strategy::Entrypoint);
+        }
+        if self.service_name.is_some() {
+            implied.push(Strategy::Systemd);
+        }
+        if self.reload_command.is_some() {
+            implied.push(Strategy::Static);
+        }
+
+        let explicit = !self.strategy.is_empty();
+        let strategies = match self.strategy.as_slice() {
+            [] if implied.len() > 1 => {
+                let fields: Vec<String> = implied
+                    .iter()
+                    .map(|s| format!("`{}`", s.required_field()))
+                    .collect();
+                return Err(DeployError::Config(format!(
+                    "{} are set together, set `strategy` to pick one or list them in order",
+                    fields.join(" and ")
+                )));
+            }
+            [] => vec![implied.first().copied().unwrap_or(Strategy::Compose)],
+            strategies => strategies.to_vec(),
+        };
+
+        for strategy in &strategies {
+            match strategy {
+                Strategy::Entrypoint if self.entrypoint.is_empty() => {
+                    return Err(DeployError::Config(
+                        "The `entrypoint` strategy requires an `entrypoint`".to_string(),
+                    ))
+                }
+                Strategy::Systemd if self.service_name.is_none() => {
+                    return Err(DeployError::Config(
+                        "The `systemd` strategy requires a `service_name`".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(other) = implied.iter().find(|s| !strategies.contains(s)) {
+            let listed: Vec<String> = strategies.iter().map(Strategy::to_string).collect();
+            return Err(DeployError::Config(format!(
+                "`strategy` is `{}` but `{}` is also set",
+                listed.join(", "),
+                other.required_field()
+            )));
+        }
+
+        if strategies.contains(&Strategy::Compose) && !self.has_compose_file() {
+            let msg = "No compose file found among the artifacts";
+            if explicit {
+                return Err(DeployError::Config(msg.to_string()));
+            }
+            warn!("{}, expecting one to already exist on the host", msg);
+        }
+
+        self.strategy = strategies.clone();
+        Ok(strategies)
+    }
+
+    /// Whether a compose file is shipped, either as a file artifact or at the
+    /// top level of a directory artifact.
+    fn has_compose_file(&self) -> bool {
+        self.artifacts.iter().any(|artifact| {
+            let src = Path::new(artifact.src());
+            if src.is_dir() && artifact.dest().is_none() {
+                return COMPOSE_FILES.iter().any(|f| src.join(f).is_file());
+            }
+            let name = artifact
+                .dest()
+                .map(Path::new)
+                .unwrap_or(src)
+                .file_name()
+                .and_then(|name| name.to_str());
+            name.is_some_and(|name| COMPOSE_FILES.contains(&name))
+        })
+    }
+
+    pub fn build_command(&self) -> &BuildCommand {
+        &self.build_command
+    }
+    /// Times a failed build command is run again, after a short delay, before
+    /// the deploy gives up. For builds that flake on network fetches.
+    pub fn build_retries(&self) -> u32 {
+        self.build_retries.unwrap_or(0)
+    }
+    /// Docker image to run the build command in, mounting the project
+    /// directory at `/app`.
+    pub fn build_image(&self) -> Option<&str> {
+        self.build_image.as_deref()
+    }
+    /// Regex run against the build command's stdout to find a file it
+    /// produced, e.g. a content-hashed bundle, which is then deployed as an
+    /// extra artifact. The first capture group is the path when there is
+    /// one, the whole match otherwise.
+    pub fn build_output_from_stdout(&self) -> Option<&str> {
+        self.build_output_from_stdout.as_deref()
+    }
+    /// Local command run after the build, whose stdout lists more artifact
+    /// paths, one per line. Given in the same forms as `build_command`.
+    pub fn artifacts_command(&self) -> &BuildCommand {
+        &self.artifacts_command
+    }
+    /// Copy of the app with `src` added to its artifacts.
+    pub fn with_artifact(&self, src: String) -> App {
+        let mut app = self.clone();
+        app.artifacts.push(Artifact {
+            src,
+            dest: None,
+            strip_prefix: None,
+            sha256: None,
+        });
+        app
+    }
+    /// Copy of the app with `artifacts` instead of its own.
+    pub fn with_artifacts(&self, artifacts: Vec<Artifact>) -> App {
+        App {
+            artifacts,
+            ..self.clone()
+        }
+    }
+    /// Copy of the app deploying to `host_path` instead.
+    pub fn with_host_path(&self, host_path: String) -> App {
+        App {
+            host_path,
+            ..self.clone()
+        }
+    }
+    pub fn shell(&self) -> bool {
+        self.shell
+    }
+    /// Local command run after each host is restarted, failing the deploy
+    /// when it exits non-zero. Given in the same forms as `build_command`.
+    pub fn smoke_test(&self) -> &BuildCommand {
+        &self.smoke_test
+    }
+    /// URL requested from the host after the restart, before the smoke test,
+    /// so the first real request doesn't hit a cold app. Responses and
+    /// errors are ignored.
+    pub fn warmup_url(&self) -> Option<&str> {
+        self.warmup_url.as_deref()
+    }
+    /// Number of warm-up requests sent to `warmup_url`, one by default.
+    pub fn warmup_requests(&self) -> u32 {
+        self.warmup_requests.unwrap_or(1)
+    }
+    /// Remote commands run in `host_path` after extraction, before restart.
+    pub fn after_extract(&self) -> &Vec<String> {
+        &self.after_extract
+    }
+    /// Host-specific files, relative to `host_path`, that must not be
+    /// replaced by a different local copy without confirmation.
+    pub fn sticky_files(&self) -> &Vec<String> {
+        &self.sticky_files
+    }
+    /// Local paths, files or directories, of which at least one must have
+    /// changed since the last successful deploy for a new one to run. Empty
+    /// means always deploy.
+    pub fn when_changed(&self) -> &Vec<String> {
+        &self.when_changed
+    }
+    /// Local file that ends up at `path` relative to `host_path`, if any
+    /// artifact provides one.
+    pub fn local_source(&self, path: &str) -> Option<PathBuf> {
+        self.artifacts
+            .iter()
+            .find_map(|artifact| artifact.local_path_for(path))
+            .filter(|local| local.is_file())
+    }
+    /// Size limit in bytes for any single file going into the archive.
+    pub fn max_artifact_size(&self) -> Option<u64> {
+        self.max_artifact_size
+    }
+    /// Size limit in bytes for the finished archive.
+    pub fn max_archive_size(&self) -> Option<u64> {
+        self.max_archive_size
+    }
+    /// How many levels of subdirectories of a directory artifact are walked,
+    /// unlimited when `None`.
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+    /// Whether every file of the archive is checked to be under `host_path`
+    /// after extraction, failing the deploy on a partial one.
+    pub fn verify_file_count(&self) -> bool {
+        self.verify_file_count
+    }
+    /// Whether the archive manifest is uploaded into `host_path` too.
+    pub fn upload_manifest(&self) -> bool {
+        self.upload_manifest
+    }
+    /// File in `host_path` recording the last successful deploy, for
+    /// monitoring to read.
+    pub fn deploy_marker(&self) -> &str {
+        self.deploy_marker.as_deref().unwrap_or(".last-deploy")
+    }
+    /// File in `host_path` recording the last failed deploy, removed again
+    /// by the next successful one.
+    pub fn deploy_failed_marker(&self) -> &str {
+        self.deploy_failed_marker
+            .as_deref()
+            .unwrap_or(".last-deploy-failed")
+    }
+    /// minisign secret key the archive is signed with, its signature being
+    /// uploaded next to it as `<archive_name>.minisig`.
+    pub fn sign_key(&self) -> Option<&str> {
+        self.sign_key.as_deref()
+    }
+    /// minisign public key, e.g. `RWQ...`, the signature is verified with on
+    /// hosts that have `minisign` installed.
+    pub fn sign_public_key(&self) -> Option<&str> {
+        self.sign_public_key.as_deref()
+    }
+    pub fn run_as(&self) -> &RunAs {
+        &self.run_as
+    }
+    pub fn overwrite(&self) -> Overwrite {
+        self.overwrite
+    }
+    pub fn on_failure(&self) -> OnFailure {
+        self.on_failure
+    }
+    pub fn compression(&self) -> Compression {
+        self.compression.unwrap_or(Compression::Stored)
+    }
+    /// Compression level for the archive entries, the method's default when
+    /// unset.
+    pub fn compression_level(&self) -> Option<i64> {
+        self.compression_level
+    }
+    /// Extensions of files already compressed, like `png` or `zip`, which
+    /// are stored as is whatever `compression` is. Lowercase, without the
+    /// leading `.`. Not used for a git `source`, archived by git itself.
+    pub fn store_extensions(&self) -> Vec<String> {
+        self.store_extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_ascii_lowercase())
+            .collect()
+    }
+
+    /// Errors when nothing would go into the archive, and warns when
+    /// `artifacts` are set but ignored.
+    pub fn check_source(&self) -> Result<()> {
+        match self.source {
+            Source::Artifacts if self.artifacts.is_empty() && self.artifacts_command.is_empty() => {
+                Err(DeployError::Config(
+                    "`artifacts` is empty, list what to deploy, set `artifacts_command` or set `\"source\": \"git\"`"
+                        .to_string(),
+                ))
+            }
+            Source::Git if self.compose_build_local() => Err(DeployError::Config(
+                "`compose_build_local` can't ship images with `\"source\": \"git\"`".to_string(),
+            )),
+            Source::Git if !self.artifacts.is_empty() || !self.artifacts_command.is_empty() => {
+                warn!("`artifacts` and `artifacts_command` are ignored with `\"source\": \"git\"`");
+                Ok(())
+            }
+            Source::Artifacts | Source::Git => Ok(()),
+        }
+    }
+
+    /// Errors when `remote_archive_name` isn't a plain file name, which would
+    /// upload the archive outside of `host_path`.
+    pub fn check_remote_archive_name(&self) -> Result<()> {
+        let Some(name) = &self.remote_archive_name else {
+            return Ok(());
+        };
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(DeployError::Config(format!(
+                "`remote_archive_name` `{}` must be a file name, without path separators",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Errors when `compression_level` is set for a method that takes no
+    /// level, or is outside the method's range.
+    pub fn check_compression_level(&self) -> Result<()> {
+        let Some(level) = self.compression_level else {
+            return Ok(());
+        };
+        match self.compression().levels() {
+            Some(levels) if levels.contains(&level) => Ok(()),
+            Some(levels) => Err(DeployError::Config(format!(
+                "`compression_level` {} is out of range for `{}` compression, expected {} to {}",
+                level,
+                self.compression(),
+                levels.start(),
+                levels.end()
+            ))),
+            None => Err(DeployError::Config(format!(
+                "`compression_level` can't be used with `{}` compression",
+                self.compression()
+            ))),
+        }
+    }
+
+    /// Fills fields left unset in `deploy.json` from the global config.
+    pub fn apply_defaults(&mut self, global: &GlobalConfig) {
+        self.compression = self.compression.or(global.compression);
+        self.compression_level = self.compression_level.or(global.compression_level);
+    }
+
+    /// Restricts the artifacts to the given subset, erroring on any entry that
+    /// isn't part of the configured artifacts.
+    pub fn retain_artifacts(&mut self, only: &[String]) -> Result<()> {
+        if let Some(missing) = only.iter().find(|o| {
+            !self
+                .artifacts
+                .iter()
+                .any(|a| Path::new(a.src()) == Path::new(o))
+        }) {
+            return Err(DeployError::Config(format!(
+                "Artifact `{}` is not listed in `deploy.json` artifacts",
+                missing
+            )));
+        }
+        self.artifacts
+            .retain(|a| only.iter().any(|o| Path::new(a.src()) == Path::new(o)));
+        Ok(())
+    }
+}
+
+/// Permission bits from the octal string `mode` of `field`.
+fn octal_mode(field: &str, mode: Option<&str>) -> Result<Option<u16>> {
+    mode.map(|mode| {
+        u16::from_str_radix(mode, 8)
+            .ok()
+            .filter(|bits| *bits <= 0o7777)
+            .ok_or_else(|| {
+                DeployError::Config(format!(
+                    "`{}` `{}` isn't an octal mode such as `755`",
+                    field, mode
+                ))
+            })
+    })
+    .transpose()
+}
+
+/// File names `docker-compose` picks up by default.
+const COMPOSE_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// How the app is (re)started on the host after extraction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Make each `entrypoint` executable, running them in order when
+    /// `run_entrypoint` is set.
+    Entrypoint,
+    /// Run `docker-compose build` and `docker-compose up -d` in `host_path`.
+    Compose,
+    /// Restart the `service_name` systemd unit.
+    Systemd,
+    /// Serve the extracted files as they are, running `reload_command` to
+    /// pick them up.
+    Static,
+}
+
+impl Strategy {
+    /// The field only this strategy uses.
+    fn required_field(&self) -> &'static str {
+        match self {
+            Strategy::Entrypoint => "entrypoint",
+            Strategy::Compose => "compose file",
+            Strategy::Systemd => "service_name",
+            Strategy::Static => "reload_command",
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::Entrypoint => "entrypoint",
+            Strategy::Compose => "compose",
+            Strategy::Systemd => "systemd",
+            Strategy::Static => "static",
+        })
+    }
+}
+
+/// A file or directory to include in the archive.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(from = "ArtifactSpec")]
+pub struct Artifact {
+    src: String,
+    dest: Option<String>,
+    strip_prefix: Option<String>,
+    sha256: Option<String>,
+}
+
+/// Artifacts are either a plain path or an object allowing to rename the
+/// entry in the archive, e.g. `{ "src": "env.production", "dest": ".env" }`,
+/// or to strip a leading path from a directory's entries, e.g.
+/// `{ "src": "build", "strip_prefix": "dist" }`. An `http(s)://` URL `src`
+/// is downloaded at deploy time, checked against `sha256` when set.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum ArtifactSpec {
+    Path(String),
+    Object {
+        src: String,
+        dest: Option<String>,
+        strip_prefix: Option<String>,
+        sha256: Option<String>,
+    },
+}
+
+impl From<ArtifactSpec> for Artifact {
+    fn from(spec: ArtifactSpec) -> Artifact {
+        match spec {
+            ArtifactSpec::Path(src) => Artifact {
+                src,
+                dest: None,
+                strip_prefix: None,
+                sha256: None,
+            },
+            ArtifactSpec::Object {
+                src,
+                dest,
+                strip_prefix,
+                sha256,
+            } => Artifact {
+                src,
+                dest,
+                strip_prefix,
+                sha256,
+            },
+        }
+    }
+}
+
+impl Artifact {
+    /// Local path of the artifact.
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+    /// Name of the artifact in the archive. Directory contents are placed
+    /// under it instead of at the archive root.
+    pub fn dest(&self) -> Option<&str> {
+        self.dest.as_deref()
+    }
+    /// Leading path removed from the entries of a directory artifact, which
+    /// every entry must start with. Has no trailing `/`.
+    pub fn strip_prefix(&self) -> Option<&str> {
+        self.strip_prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_matches('/'))
+            .filter(|prefix| !prefix.is_empty())
+    }
+    /// Whether `src` is a URL to download rather than a local path.
+    pub fn is_url(&self) -> bool {
+        self.src.starts_with("https://") || self.src.starts_with("http://")
+    }
+    /// Expected SHA-256 of a downloaded artifact, as lowercase hex.
+    pub fn sha256(&self) -> Option<String> {
+        self.sha256.as_deref().map(str::to_ascii_lowercase)
+    }
+    /// Copy of a URL artifact with its download at `path` as `src`, landing
+    /// at `dest` or the last segment of the URL. `None` when the URL ends in
+    /// `/` and there's no `dest`.
+    pub fn downloaded(&self, path: String) -> Option<Artifact> {
+        let dest = match &self.dest {
+            Some(dest) => dest.clone(),
+            None => self
+                .src
+                .split(['?', '#'])
+                .next()
+                .and_then(|url| url.rsplit('/').next())
+                .filter(|name| !name.is_empty())?
+                .to_string(),
+        };
+        Some(Artifact {
+            src: path,
+            dest: Some(dest),
+            strip_prefix: self.strip_prefix.clone(),
+            sha256: None,
+        })
+    }
+    /// Local path of the file that lands at `path`, either the artifact
+    /// itself or a file inside an artifact directory. Mirrors the archive
+    /// layout: files sit at `dest` or their file name, directory contents
+    /// under `dest` or the archive root, less `strip_prefix`.
+    pub fn local_path_for(&self, path: &str) -> Option<PathBuf> {
+        let src = Path::new(&self.src);
+        if src.is_dir() {
+            let rest = match self.dest() {
+                Some(dest) => path
+                    .strip_prefix(dest.trim_end_matches('/'))?
+                    .strip_prefix('/')?,
+                None => path,
+            };
+            return Some(match self.strip_prefix() {
+                Some(prefix) => src.join(prefix).join(rest),
+                None => src.join(rest),
+            });
+        }
+
+        let name = match self.dest() {
+            Some(dest) => dest,
+            None => src.file_name()?.to_str()?,
+        };
+        (name == path).then(|| src.to_path_buf())
+    }
+    /// Whether the artifact ends up at `path` relative to `host_path`.
+    pub fn lands_at(&self, path: &str) -> bool {
+        match self.dest() {
+            Some(dest) => dest == path,
+            None => self.src == path,
+        }
+    }
+}
+
+/// Schema for the string fields read with [`one_or_many`].
+fn one_or_many_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            { "type": "string" },
+            { "type": "array", "items": { "type": "string" } }
+        ]
+    })
+}
+
+fn one_or_many_strategies(generator: &mut SchemaGenerator) -> Schema {
+    let strategy = generator.subschema_for::<Strategy>();
+    json_schema!({
+        "anyOf": [strategy, { "type": "array", "items": strategy }]
+    })
+}
+
+/// Accepts either a single value or a list of values.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// The build command, either as a single shell-like string (split with
+/// shell quoting rules) or as an explicit list of arguments.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum BuildCommand {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl Default for BuildCommand {
+    fn default() -> BuildCommand {
+        BuildCommand::Args(Vec::new())
+    }
+}
+
+impl BuildCommand {
+    /// Whether there's no build to run.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            BuildCommand::Line(line) => line.trim().is_empty(),
+            BuildCommand::Args(args) => args.is_empty(),
+        }
+    }
+
+    pub fn argv(&self) -> Result<Vec<String>> {
+        let argv = match self {
+            BuildCommand::Line(line) => shell_words::split(line).map_err(|err| {
+                DeployError::Config(format!("Error parsing build command: {}", err))
+            })?,
+            BuildCommand::Args(args) => args.clone(),
+        };
+        if argv.is_empty() {
+            return Err(DeployError::Config("Build command is empty".to_string()));
+        }
+        Ok(argv)
+    }
+
+    /// Renders the command as a single line suitable for `sh -c`.
+    pub fn shell_line(&self) -> String {
+        match self {
+            BuildCommand::Line(line) => line.clone(),
+            BuildCommand::Args(args) => shell_words::join(args),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Host {
+    name: Option<String>,
+    description: Option<String>,
+    /// Roles such as `web` or `worker`, for `--role` to select the host by.
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    transport: Transport,
+    // Required unless `transport` is `local`, see `Host::check`.
+    #[serde(default)]
+    key_path: String,
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    host: String,
+    #[serde(default)]
+    port: u16,
+    known_hosts: Option<KnownHostsPolicy>,
+    retries: Option<u32>,
+    deploy_retries: Option<u32>,
+    keepalive: Option<u64>,
+    /// Compress the SSH connection like `ssh -C`, for uploads of text-heavy,
+    /// stored archives over slow links. Costs CPU on both ends, and gains
+    /// little on fast links or already compressed archives.
+    #[serde(default)]
+    ssh_compression: bool,
+    #[serde(default)]
+    sftp: SftpConfig,
+    /// Raw `ssh -o` options such as `ServerAliveInterval=15`, passed to
+    /// `ssh` verbatim.
+    #[serde(default)]
+    ssh_options: Vec<String>,
+    /// Alternate `ssh` binary to open the connection with instead of the one
+    /// on `PATH`, which still forwards each command over it.
+    ssh_binary: Option<PathBuf>,
+    platform: Option<Platform>,
+    transfer: Option<Transfer>,
+    /// Environment variables set for the restart commands and the
+    /// `after_extract` hooks on this host, like `COMPOSE_PROJECT_NAME`.
+    /// Unix hosts only.
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    /// Expected host key, either as `<type> <base64>` like in
+    /// `known_hosts`, as the bare base64 key or as a `SHA256:` fingerprint.
+    /// When set only this key is accepted, whatever `known_hosts` holds.
+    host_key: Option<String>,
+}
+
+/// Tuning knobs for the SFTP client. Unset fields keep the
+/// `openssh-sftp-client` defaults, which suit most links.
+#[derive(Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct SftpConfig {
+    /// Requests buffered before they're flushed without waiting for
+    /// `flush_interval_ms` (default 100). Raising it lets more writes be in
+    /// flight on high-latency links.
+    max_pending_requests: Option<NonZeroU16>,
+    /// Longest time in milliseconds a request waits in the write buffer
+    /// before being sent (default 0.5). Larger values group more writes per
+    /// packet at the cost of latency.
+    flush_interval_ms: Option<f64>,
+    /// Bytes sent per upload write (default 32 KiB). Writes above the
+    /// server's own limit are split into several requests.
+    max_write_len: Option<NonZeroUsize>,
+    /// Buffer in bytes for outgoing requests (default 100).
+    requests_buffer_size: Option<NonZeroUsize>,
+    /// Buffer in bytes for incoming responses (default 1024).
+    responses_buffer_size: Option<NonZeroUsize>,
+    /// Times a failed write is retried, with a doubling backoff from 200ms,
+    /// when the error may be transient (default 3). Only a momentarily
+    /// unwritable, interrupted or timed out connection is retried; errors
+    /// from the server, such as permission, missing file, disk full or its
+    /// generic failure, fail the upload right away.
+    write_retries: Option<u32>,
+}
+
+impl SftpConfig {
+    pub fn options(&self) -> SftpOptions {
+        let mut options = SftpOptions::new();
+        if let Some(max) = self.max_pending_requests {
+            options = options.max_pending_requests(max);
+        }
+        if let Some(ms) = self.flush_interval_ms {
+            options = options.flush_interval(Duration::from_secs_f64(ms / 1000.0));
+        }
+        if let Some(size) = self.requests_buffer_size {
+            options = options.requests_buffer_size(size);
+        }
+        if let Some(size) = self.responses_buffer_size {
+            options = options.responses_buffer_size(size);
+        }
+        options
+    }
+
+    pub fn max_write_len(&self) -> Option<usize> {
+        self.max_write_len.map(NonZeroUsize::get)
+    }
+
+    pub fn write_retries(&self) -> u32 {
+        self.write_retries.unwrap_or(upload::WRITE_RETRIES)
+    }
+}
+
+impl Host {
+    /// Host given on the command line as `ssh://user@host[:port]`, with
+    /// everything else left to its defaults.
+    pub fn from_url(url: &str, key_path: &str) -> Result<Host> {
+        let invalid = |reason: &str| {
+            DeployError::Config(format!(
+                "Invalid host URL `{}`: {}, expected `ssh://user@host[:port]`",
+                url, reason
+            ))
+        };
+        let rest = url
+            .strip_prefix("ssh://")
+            .ok_or_else(|| invalid("missing `ssh://`"))?
+            .trim_end_matches('/');
+        let (user, address) = rest
+            .split_once('@')
+            .ok_or_else(|| invalid("missing user"))?;
+        if user.is_empty() {
+            return Err(invalid("missing user"));
+        }
+        // An IPv6 address is bracketed so its colons aren't read as the port.
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| invalid("unclosed `[`"))?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match address.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        if host.is_empty() || host.contains(['/', '@']) {
+            return Err(invalid("missing or invalid host"));
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("invalid port"))?,
+            None => 22,
+        };
+
+        Ok(Host {
+            name: None,
+            description: None,
+            roles: Vec::new(),
+            transport: Transport::Ssh,
+            key_path: key_path.to_string(),
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+            known_hosts: None,
+            retries: None,
+            deploy_retries: None,
+            keepalive: None,
+            ssh_compression: false,
+            sftp: SftpConfig::default(),
+            ssh_options: Vec::new(),
+            ssh_binary: None,
+            platform: None,
+            transfer: None,
+            env: BTreeMap::new(),
+            host_key: None,
+        })
+    }
+    /// Errors when a field the host's transport needs is missing, or an
+    /// `env` name isn't a valid shell variable name.
+    pub fn check(&self) -> Result<(), String> {
+        let invalid = |name: &str| {
+            !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        if let Some(name) = self.env.keys().find(|name| invalid(name)) {
+            return Err(format!(
+                "host `{}`: invalid `env` variable name `{}`",
+                self.name(),
+                name
+            ));
+        }
+        match self.transport {
+            Transport::Ssh => {
+                let missing = [
+                    ("key_path", self.key_path.is_empty()),
+                    ("user", self.user.is_empty()),
+                    ("host", self.host.is_empty()),
+                    ("port", self.port == 0),
+                ]
+                .into_iter()
+                .find(|(_, missing)| *missing);
+                match missing {
+                    Some((field, _)) => Err(format!(
+                        "host `{}` is missing field `{}`",
+                        self.name(),
+                        field
+                    )),
+                    None => Ok(()),
+                }
+            }
+            Transport::Local if self.name.is_none() => {
+                Err("a `\"transport\": \"local\"` host needs a `name`".to_string())
+            }
+            Transport::Local if self.platform() == Platform::Windows => Err(format!(
+                "host `{}`: `\"transport\": \"local\"` is only supported on unix",
+                self.name()
+            )),
+            Transport::Local => Ok(()),
+        }
+    }
+    pub fn to_url(&self) -> String {
+        if self.host.contains(':') {
+            return format!("ssh://{}@[{}]:{}", self.user, self.host, self.port);
+        }
+        format!("ssh://{}@{}:{}", self.user, self.host, self.port)
+    }
+    /// Name used to select the host, falling back to its address.
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.host)
+    }
+    /// Label shown in the interactive host picker.
+    pub fn label(&self) -> String {
+        let address = match self.transport {
+            Transport::Ssh => &self.host,
+            Transport::Local => "local",
+        };
+        match &self.description {
+            Some(description) => format!("{} - {} ({})", self.name(), description, address),
+            None => format!("{} ({})", self.name(), address),
+        }
+    }
+    pub fn key_path(&self) -> &String {
+        &self.key_path
+    }
+    pub fn known_hosts(&self) -> KnownHostsPolicy {
+        self.known_hosts.unwrap_or(KnownHostsPolicy::Strict)
+    }
+    pub fn sftp(&self) -> &SftpConfig {
+        &self.sftp
+    }
+    pub fn ssh_options(&self) -> &Vec<String> {
+        &self.ssh_options
+    }
+    pub fn ssh_binary(&self) -> Option<&Path> {
+        self.ssh_binary.as_deref()
+    }
+    pub fn host_key(&self) -> Option<&str> {
+        self.host_key.as_deref()
+    }
+    pub fn address(&self) -> &str {
+        &self.host
+    }
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn platform(&self) -> Platform {
+        self.platform.unwrap_or_default()
+    }
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+    pub fn roles(&self) -> &Vec<String> {
+        &self.roles
+    }
+    pub fn ssh_compression(&self) -> bool {
+        self.ssh_compression
+    }
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+    pub fn transfer(&self) -> Transfer {
+        self.transfer.unwrap_or_default()
+    }
+    /// Number of extra connection attempts after the first one fails.
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(0)
+    }
+    /// Number of times the whole remote phase is retried on a fresh session
+    /// after the connection drops mid-deploy.
+    pub fn deploy_retries(&self) -> u32 {
+        self.deploy_retries.unwrap_or(0)
+    }
+    /// Interval for ssh's `ServerAliveInterval`, so idle-timeout firewalls
+    /// don't drop the connection during long remote commands. `None` when
+    /// disabled with `0` or set through `ssh_options` instead.
+    pub fn keepalive(&self) -> Option<Duration> {
+        let overridden = self.ssh_options.iter().any(|option| {
+            option
+                .to_ascii_lowercase()
+                .starts_with("serveraliveinterval")
+        });
+        match self.keepalive.unwrap_or(15) {
+            0 => None,
+            _ if overridden => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Fills fields left unset in `hosts.json` from the global config.
+    pub fn apply_defaults(&mut self, global: &GlobalConfig) {
+        self.known_hosts = self.known_hosts.or(global.known_hosts);
+        self.retries = self.retries.or(global.retries);
+        self.deploy_retries = self.deploy_retries.or(global.deploy_retries);
+        self.keepalive = self.keepalive.or(global.keepalive);
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::archive::delta_archive;
+use crate::cleanup::TempFile;
+use crate::config::{App, Host};
+use crate::error::{DeployError, Result};
+use crate::manifest::Manifest;
+
+/// What a host is missing from the archive with
+/// `--only-upload-changed-entries`, against the manifest last deployed to it.
+#[derive(Debug)]
+pub struct Delta {
+    /// Local archive holding only the new and changed entries, removed
+    /// along with the delta.
+    pub archive: TempFile,
+    /// Number of files in `archive`.
+    pub changed: usize,
+    /// Files of the previous deploy that are no longer in the archive, to be
+    /// removed from `host_path` after extraction.
+    pub removed: Vec<String>,
+}
+
+impl Delta {
+    /// Builds the delta archive for `host`, `None` when it has no recorded
+    /// deploy to diff against and gets the full archive.
+    pub fn prepare(app: &App, host: &Host) -> Result<Option<Delta>> {
+        let deployed_path = Manifest::deployed_path(app.archive_name(), host.name());
+        let Some(previous) = Manifest::load(&deployed_path) else {
+            debug!(path = %deployed_path, "no deployed manifest, uploading the full archive");
+            return Ok(None);
+        };
+        let manifest_path = Manifest::path_for(app.archive_path());
+        let current = Manifest::load(&manifest_path).ok_or_else(|| {
+            DeployError::Build(format!("Error reading manifest `{}`", manifest_path))
+        })?;
+
+        let changed: HashSet<&str> = current.changed_since(&previous).into_iter().collect();
+        let archive = TempFile::new(archive_path(app, host));
+        delta_archive(Path::new(app.archive_path()), archive.path(), &changed)?;
+
+        Ok(Some(Delta {
+            archive,
+            changed: changed.len(),
+            removed: current
+                .removed_since(&previous)
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }))
+    }
+}
+
+/// Local path of the delta archive for `host`, next to the full one.
+pub fn archive_path(app: &App, host: &Host) -> String {
+    format!(
+        "{}.delta.zip",
+        Manifest::deployed_path(app.archive_path(), host.name())
+    )
+}
+
+/// Keeps the manifest of the archive just deployed to `host`, for the next
+/// incremental deploy to diff against.
+pub fn record(app: &App, host: &Host) -> Result<()> {
+    let manifest_path = Manifest::path_for(app.archive_path());
+    let deployed_path = Manifest::deployed_path(app.archive_name(), host.name());
+    fs::copy(&manifest_path, &deployed_path).map_err(|err| {
+        DeployError::Other(format!(
+            "Error copying manifest to `{}`: {}",
+            deployed_path, err
+        ))
+    })?;
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use std::env;
+use std::fs::File;
+use std::path::Path;
+
+use console::style;
+
+use crate::cli::Cli;
+use crate::config::{App, GlobalConfig, Host, Strategy, Transfer, Transport};
+use crate::error::{DeployError, Result};
+use crate::remote::{unwrap_command_stderr, Remote};
+use crate::ssh::SshOverrides;
+use crate::vars::Vars;
+use crate::{
+    close_session, configured_hosts, connect, deploy_file_path, enter_deploy_dir, hosts, output,
+};
+
+/// Runs every check, printing a pass/fail checklist with a hint for each
+/// failure. Fails when any check did.
+pub async fn doctor(cli: &Cli, global: &GlobalConfig, vars: &Vars) -> Result<()> {
+    let mut checks = Checklist::default();
+
+    let hosts = match configured_hosts(cli, vars) {
+        Ok(hosts) => {
+            checks.pass(format!("Hosts config loaded ({} host(s))", hosts.len()));
+            Some(hosts)
+        }
+        Err(err) => {
+            checks.fail(
+                err,
+                "Create `~/ec2-deploy/hosts.json`, or pass `--config <path>`",
+            );
+            None
+        }
+    };
+
+    let deploy_file = deploy_file_path(cli);
+    let app = match App::load(&deploy_file, vars, cli.profile.as_deref()) {
+        Ok(mut app) => {
+            checks.pass(format!("Deploy file `{}` loaded", deploy_file.display()));
+            app.apply_defaults(global);
+            Some(app)
+        }
+        Err(err) => {
+            checks.fail(
+                err,
+                "Run from the project directory, or pass `--deploy-file <path>`",
+            );
+            None
+        }
+    };
+
+    if let Some(app) = &app {
+        check_app(&mut checks, &deploy_file, app);
+    }
+    let strategies = match app.map(|mut app| app.resolve_strategy()) {
+        Some(Ok(strategies)) => strategies,
+        Some(Err(err)) => {
+            checks.fail(
+                err,
+                "Fix `strategy` or the restart settings in the deploy file",
+            );
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+
+    if let Some(hosts) = hosts {
+        match hosts::select_hosts(hosts, cli) {
+            Ok(mut selected) => {
+                for host in selected.iter_mut() {
+                    host.apply_defaults(global);
+                    check_host(&mut checks, host, &strategies).await;
+                }
+            }
+            Err(err) => checks.fail(err, "Pick a host with `--host <name>`, or use `--all`"),
+        }
+    }
+
+    if checks.failed > 0 {
+        return Err(DeployError::Other(format!(
+            "{} check(s) failed",
+            checks.failed
+        )));
+    }
+    output::success("All checks passed");
+    Ok(())
+}
+
+fn check_app(checks: &mut Checklist, deploy_file: &Path, app: &App) {
+    if let Err(err) = enter_deploy_dir(deploy_file) {
+        checks.fail(err, "Make sure the deploy file's directory exists");
+        return;
+    }
+
+    if app.build_command().is_empty() {
+        checks.pass("No build command, the build is skipped");
+    } else {
+        check_build_command(checks, app);
+    }
+
+    if app.sign_key().is_some() {
+        if on_path("minisign") {
+            checks.pass("`minisign` found for `sign_key`");
+        } else {
+            checks.fail(
+                "`minisign` not found, needed to sign the archive with `sign_key`",
+                "Install minisign, or remove `sign_key`",
+            );
+        }
+    }
+
+    for artifact in app.artifacts().iter().filter(|a| !a.is_url()) {
+        if !Path::new(artifact.src()).exists() {
+            checks.fail(
+                format!("Artifact `{}` not found", artifact.src()),
+                "Build the app first, or fix the path in `artifacts`",
+            );
+        }
+    }
+}
+
+async fn check_host(checks: &mut Checklist, host: &Host, strategies: &[Strategy]) {
+    println!("{}", style(format!("Host {}", host.label())).bold());
+
+    if host.transport() == Transport::Local {
+        checks.pass("Local host, its remote steps run on this machine");
+        check_remote_tools(checks, &Remote::Local, host, strategies).await;
+        return;
+    }
+
+    match File::open(host.key_path()) {
+        Ok(_) => checks.pass(format!("SSH key `{}` readable", host.key_path())),
+        Err(err) => checks.fail(
+            format!("SSH key `{}` not readable: {}", host.key_path(), err),
+            "Fix `key_path` in the hosts config, or the key's permissions",
+        ),
+    }
+
+    // Commands go through the `ssh` on `PATH` even with an `ssh_binary`.
+    let mut clients = vec!["ssh".to_string()];
+    clients.extend(
+        host.ssh_binary()
+            .map(|binary| binary.to_string_lossy().into_owned()),
+    );
+    if let Some(ssh) = clients.iter().find(|ssh| !on_path(ssh)) {
+        checks.fail(
+            format!("SSH client `{}` not found", ssh),
+            "Install OpenSSH, or fix `ssh_binary` in the hosts config",
+        );
+        return;
+    }
+
+    let overrides = match SshOverrides::prepare(host) {
+        Ok(overrides) => overrides,
+        Err(err) => {
+            checks.fail(err, "Fix `ssh_options` or `ssh_binary` in the hosts config");
+            return;
+        }
+    };
+    let session = match connect(host, overrides.as_ref()).await {
+        Ok(session) => session,
+        Err(err) => {
+            checks.fail(
+                err,
+                "Check `host`, `port` and `user`, and that the security group allows SSH",
+            );
+            return;
+        }
+    };
+
+    match session.command("true").status().await {
+        Ok(status) if status.success() => checks.pass("Connected and ran `true`"),
+        Ok(status) => checks.fail(
+            format!("`true` exited with {} on the host", status),
+            "Check the remote user's login shell",
+        ),
+        Err(err) => checks.fail(
+            format!("Error running `true` on the host: {}", err),
+            "Check the remote user's login shell",
+        ),
+    }
+
+    check_remote_tools(checks, &Remote::Ssh(&session), host, strategies).await;
+
+    close_session(Some(session)).await;
+}
+
+async fn check_remote_tools(
+    checks: &mut Checklist,
+    session: &Remote<'_>,
+    host: &Host,
+    strategies: &[Strategy],
+) {
+    let mut tools = vec!["unzip"];
+    for strategy in strategies {
+        match strategy {
+            Strategy::Compose => tools.push("docker-compose"),
+            Strategy::Systemd => tools.extend(["sudo", "systemctl"]),
+            Strategy::Entrypoint | Strategy::Static => {}
+        }
+    }
+    if host.transfer() == Transfer::Scp {
+        tools.push("scp");
+    }
+    for tool in tools {
+        check_remote_tool(checks, session, tool).await;
+    }
+}
+
+async fn check_remote_tool(checks: &mut Checklist, session: &Remote<'_>, tool: &str) {
+    let found = unwrap_command_stderr(session.command("command").args(["-v", tool]))
+        .await
+        .is_ok_and(|out| out.success());
+    if found {
+        checks.pass(format!("`{}` available on the host", tool));
+    } else {
+        checks.fail(
+            format!("`{}` not found on the host", tool),
+            "Install it on the host",
+        );
+    }
+}
+
+fn check_build_command(checks: &mut Checklist, app: &App) {
+    let program = if app.build_image().is_some() {
+        Ok("docker".to_string())
+    } else if app.shell() {
+        Ok("sh".to_string())
+    } else {
+        app.build_command().argv().map(|mut argv| argv.remove(0))
+    };
+    match program {
+        Ok(program) if on_path(&program) => {
+            checks.pass(format!("Build command `{}` found", program))
+        }
+        Ok(program) => checks.fail(
+            format!("Build command `{}` not found", program),
+            "Install it or fix `build_command` in the deploy file",
+        ),
+        Err(err) => checks.fail(err, "Fix `build_command` in the deploy file"),
+    }
+}
+
+/// Whether `program` is an existing file, or found in a `PATH` directory
+/// when it's a bare name.
+fn on_path(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+#[derive(Default)]
+struct Checklist {
+    failed: usize,
+}
+
+impl Checklist {
+    fn pass(&mut self, message: impl std::fmt::Display) {
+        println!("  {} {}", style("\u{2713}").green(), message);
+    }
+
+    fn fail(&mut self, message: impl std::fmt::Display, hint: &str) {
+        self.failed += 1;
+        println!("  {} {}", style("\u{2717}").red(), message);
+        println!("    {}", style(hint).dim());
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::cleanup::TempDir;
+use crate::config::{App, Artifact};
+use crate::error::{DeployError, Result};
+use crate::events::{emit, DeployEvent};
+
+/// Downloads the app's URL artifacts into a private temporary directory,
+/// returning a copy of the app with those files as artifacts instead. `None`
+/// when there are no URL artifacts. The files are removed once the returned
+/// `TempDir` is dropped.
+pub async fn download_artifacts(app: &App) -> Result<Option<(App, TempDir)>> {
+    if !app.artifacts().iter().any(Artifact::is_url) {
+        return Ok(None);
+    }
+
+    let dir = TempDir::new("download").map_err(|err| {
+        DeployError::Build(format!("Error creating the download directory: {}", err))
+    })?;
+    let mut artifacts = Vec::new();
+    for (i, artifact) in app.artifacts().iter().enumerate() {
+        if !artifact.is_url() {
+            artifacts.push(artifact.clone());
+            continue;
+        }
+
+        let download = dir.path().join(i.to_string());
+        let url = artifact.src();
+        let bytes = fetch(url, &download).await?;
+        if let Some(expected) = artifact.sha256() {
+            check_sha256(url, &download, &expected)?;
+        }
+        emit(DeployEvent::Downloaded { url, bytes });
+
+        let path = download.display().to_string();
+        artifacts.push(artifact.downloaded(path).ok_or_else(|| {
+            DeployError::Config(format!(
+                "Artifact `{}` needs a `dest`, the URL has no file name",
+                url
+            ))
+        })?);
+    }
+
+    Ok(Some((app.with_artifacts(artifacts), dir)))
+}
+
+fn check_sha256(url: &str, download: &Path, expected: &str) -> Result<()> {
+    let content = fs::read(download).map_err(|err| {
+        DeployError::Build(format!("Error reading download of `{}`: {}", url, err))
+    })?;
+    let actual: String = Sha256::digest(&content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if actual != expected {
+        return Err(DeployError::Build(format!(
+            "Download of `{}` has SHA-256 {}, expected {}",
+            url, actual, expected
+        )));
+    }
+    Ok(())
+}
+
+/// Writes the body of `url` to the new file `download`, returning its size in
+/// bytes. An existing file at that path is an error rather than overwritten.
+#[cfg(feature = "http")]
+async fn fetch(url: &str, download: &Path) -> Result<u64> {
+    use tokio::io::AsyncWriteExt;
+
+    let error = |err: &dyn std::fmt::Display| {
+        DeployError::Build(format!("Error downloading `{}`: {}", url, err))
+    };
+
+    let mut response = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| error(&err))?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(download)
+        .await
+        .map_err(|err| error(&err))?;
+    let mut bytes = 0;
+    while let Some(chunk) = response.chunk().await.map_err(|err| error(&err))? {
+        file.write_all(&chunk).await.map_err(|err| error(&err))?;
+        bytes += chunk.len() as u64;
+    }
+    file.flush().await.map_err(|err| error(&err))?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "http"))]
+async fn fetch(url: &str, _download: &Path) -> Result<u64> {
+    Err(DeployError::Config(format!(
+        "Artifact `{}` needs ec2-deploy built with the `http` feature",
+        url
+    )))
+}
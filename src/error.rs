@@ -0,0 +1,60 @@
+use std::fmt;
+
+/// A failed deploy, grouped by the category reported through the process exit
+/// code.
+#[derive(Debug)]
+pub enum DeployError {
+    /// Missing, unreadable or invalid configuration.
+    Config(String),
+    /// The host couldn't be reached or the SFTP subsystem couldn't be started.
+    Connection(String),
+    /// The local build or the archive creation failed.
+    Build(String),
+    /// An upload, remote command or restart step failed.
+    Remote(String),
+    /// Any other failure.
+    Other(String),
+}
+
+impl DeployError {
+    /// Exit code reported for this error. Kept in sync with the table printed
+    /// by `--help`.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            DeployError::Other(_) => 1,
+            DeployError::Config(_) => 2,
+            DeployError::Connection(_) => 3,
+            DeployError::Build(_) => 4,
+            DeployError::Remote(_) => 5,
+        }
+    }
+
+    /// Wraps the error in what was being done when it happened, keeping its
+    /// category. Each wrap is printed as a line above the error it caused.
+    pub fn context(self, context: impl fmt::Display) -> DeployError {
+        let wrap = |msg: String| format!("{}\n  caused by: {}", context, msg);
+        match self {
+            DeployError::Config(msg) => DeployError::Config(wrap(msg)),
+            DeployError::Connection(msg) => DeployError::Connection(wrap(msg)),
+            DeployError::Build(msg) => DeployError::Build(wrap(msg)),
+            DeployError::Remote(msg) => DeployError::Remote(wrap(msg)),
+            DeployError::Other(msg) => DeployError::Other(wrap(msg)),
+        }
+    }
+}
+
+impl fmt::Display for DeployError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeployError::Config(msg)
+            | DeployError::Connection(msg)
+            | DeployError::Build(msg)
+            | DeployError::Remote(msg)
+            | DeployError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for DeployError {}
+
+pub type Result<T, E = DeployError> = std::result::Result<T, E>;
@@ -0,0 +1,156 @@
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::archive::ArchiveEntry;
+use crate::cli::Step;
+use crate::error::DeployError;
+
+/// Progress of a deploy. Every status line a deploy, `--step` or `--app` run
+/// reports goes through [`emit`] as one of these, so an embedder can render
+/// its own progress instead of the CLI's output. Subcommands such as `watch`
+/// and `plan` print their own.
+#[derive(Debug)]
+pub enum DeployEvent<'a> {
+    /// The hosts `--all`, `--role` or a `--host` glob picked.
+    HostsSelected {
+        hosts: &'a [&'a str],
+    },
+    Started {
+        app: &'a str,
+    },
+    /// None of the `when_changed` paths changed since the last deploy.
+    Unchanged,
+    /// With `--interactive` or `--verbose`, the files about to be zipped.
+    EntriesListed {
+        files: &'a [&'a ArchiveEntry],
+    },
+    BuildSkipped,
+    Built,
+    /// A URL artifact finished downloading.
+    Downloaded {
+        url: &'a str,
+        bytes: u64,
+    },
+    /// The `compose_build_local` images are saved for upload.
+    ImagesSaved {
+        path: &'a str,
+    },
+    ConnectRetry {
+        error: &'a openssh::Error,
+        attempt: u32,
+        retries: u32,
+    },
+    DeployRetry {
+        error: &'a DeployError,
+        attempt: u32,
+        retries: u32,
+    },
+    BuildRetry {
+        error: &'a DeployError,
+        attempt: u32,
+        retries: u32,
+    },
+    /// With `--only-upload-changed-entries`, what the host is missing.
+    DeltaPrepared {
+        changed: usize,
+        removed: usize,
+    },
+    /// The archive, or the `--raw` file, finished uploading.
+    Transferred {
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// The archive is in place on the host and about to be extracted.
+    Uploaded,
+    /// The `--raw` file is in place on the host.
+    RawUploaded {
+        path: &'a str,
+    },
+    /// A line written by a long-running remote command.
+    RemoteOutput {
+        line: &'a str,
+    },
+    Hook {
+        command: &'a str,
+    },
+    EntrypointFound {
+        entrypoint: &'a str,
+    },
+    /// The entrypoint isn't among the artifacts, so it's uploaded on its own.
+    EntrypointUploading {
+        entrypoint: &'a str,
+    },
+    Entrypoint {
+        entrypoint: &'a str,
+    },
+    StackBuilt,
+    ServiceRestarted {
+        service: &'a str,
+    },
+    SiteReloaded,
+    /// The `warmup_url` was requested `requests` times.
+    WarmedUp {
+        url: &'a str,
+        requests: u32,
+    },
+    SmokeTest {
+        command: &'a str,
+    },
+    SmokeTestPassed,
+    /// The archive's detached signature is written.
+    ArchiveSigned {
+        path: &'a str,
+    },
+    SignatureVerified,
+    /// With `verify_file_count`, all `count` files of the archive are on the
+    /// host.
+    FilesVerified {
+        count: usize,
+    },
+    /// With `"on_failure": "rollback"`, `host_path` is back to how it was
+    /// before the failed deploy.
+    RolledBack {
+        path: &'a str,
+    },
+    ConnectionClosed,
+    HostDeployed {
+        app: &'a str,
+        host: &'a str,
+        elapsed: Duration,
+    },
+    /// A host left out because an earlier one failed.
+    HostSkipped {
+        host: &'a str,
+    },
+    /// With `--step`, the step finished on `host`.
+    StepRan {
+        step: Step,
+        host: &'a str,
+    },
+    /// With several `--app` deploy files, one failed and the next is up.
+    AppFailed {
+        error: &'a DeployError,
+    },
+    /// With several `--app` deploy files, how each went: the app's name when
+    /// it deployed, `None` when it failed.
+    AppsFinished {
+        apps: &'a [(&'a Path, Option<&'a str>)],
+    },
+}
+
+type Handler = Box<dyn Fn(&DeployEvent) + Send + Sync>;
+
+static HANDLER: OnceLock<Handler> = OnceLock::new();
+
+/// Sets the function every [`DeployEvent`] is passed to. Only the first call
+/// takes effect; until then events are dropped.
+pub fn on_event(handler: impl Fn(&DeployEvent) + Send + Sync + 'static) {
+    let _ = HANDLER.set(Box::new(handler));
+}
+
+pub fn emit(event: DeployEvent) {
+    if let Some(handler) = HANDLER.get() {
+        handler(&event);
+    }
+}
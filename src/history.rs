@@ -0,0 +1,168 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::archive::git;
+use crate::config::config_dir;
+use crate::error::{DeployError, Result};
+use crate::hosts::glob_match;
+
+/// One deploy of an app to a host, appended to `~/ec2-deploy/history.jsonl`
+/// once it finishes, whatever the project.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    app: String,
+    host: String,
+    /// Commit checked out in the deploy file's directory, if it's in a git
+    /// repository.
+    git_sha: Option<String>,
+    status: String,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("history.jsonl"))
+}
+
+/// Appends the outcome of deploying `app` to `host`. Failing to is only
+/// warned about, the deploy itself being over.
+pub fn append(app: &str, host: &str, duration: Duration, result: &Result<()>) {
+    let record = Record {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+        app: app.to_string(),
+        host: host.to_string(),
+        git_sha: git(&["rev-parse", "HEAD"])
+            .map(|sha| sha.trim().to_string())
+            .map_err(|err| debug!(%err, "no git sha for deploy history"))
+            .ok(),
+        status: if result.is_ok() { "success" } else { "failed" }.to_string(),
+        duration_ms: duration.as_millis() as u64,
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    if let Err(err) = write(&record) {
+        warn!(%err, "error writing deploy history");
+    }
+}
+
+fn write(record: &Record) -> std::result::Result<(), String> {
+    let path = path().map_err(|err| err.to_string())?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|err| err.to_string())?;
+    }
+    let mut line = serde_json::to_string(record).map_err(|err| err.to_string())?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|err| format!("`{}`: {}", path.display(), err))
+}
+
+/// Prints the last `limit` deploys, oldest first, keeping those to hosts
+/// matching the `host` name or glob and of the app named `app`.
+pub fn history(host: Option<&str>, app: Option<&str>, limit: usize) -> Result<()> {
+    let path = path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(DeployError::Other(format!(
+                "Error reading `{}`: {}",
+                path.display(),
+                err
+            )))
+        }
+    };
+
+    let records: Vec<Record> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            serde_json::from_str(line)
+                .map_err(|err| debug!(%err, "skipping unreadable history line"))
+                .ok()
+        })
+        .filter(|record: &Record| host.is_none_or(|host| glob_match(host, &record.host)))
+        .filter(|record| app.is_none_or(|app| record.app == app))
+        .collect();
+    if records.is_empty() {
+        println!("No deploys recorded");
+        return Ok(());
+    }
+
+    for record in &records[records.len().saturating_sub(limit)..] {
+        let sha = record
+            .git_sha
+            .as_deref()
+            .map_or("-", |sha| &sha[..sha.len().min(7)]);
+        println!(
+            "{}  {}  {}  {}  {}  {:.1?}",
+            utc_time(record.timestamp),
+            record.app,
+            record.host,
+            sha,
+            record.status,
+            Duration::from_millis(record.duration_ms)
+        );
+    }
+    Ok(())
+}
+
+/// `timestamp` as `YYYY-MM-DD HH:MM:SS` in UTC.
+fn utc_time(timestamp: u64) -> String {
+    let (days, secs) = (timestamp / 86_400, timestamp % 86_400);
+    // Civil date from days since 1970-01-01, after Howard Hinnant's
+    // `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utc_time_of_epoch() {
+        assert_eq!(utc_time(0), "1970-01-01 00:00:00");
+    }
+
+    #[test]
+    fn utc_time_of_leap_days() {
+        assert_eq!(utc_time(1_709_210_096), "2024-02-29 12:34:56");
+        assert_eq!(utc_time(951_782_400), "2000-02-29 00:00:00");
+    }
+
+    #[test]
+    fn utc_time_across_a_year_boundary() {
+        assert_eq!(utc_time(1_704_067_199), "2023-12-31 23:59:59");
+        assert_eq!(utc_time(1_704_067_200), "2024-01-01 00:00:00");
+    }
+}
@@ -0,0 +1,202 @@
+use crate::cli::Cli;
+use crate::config::Host;
+use crate::error::{DeployError, Result};
+use crate::events::{emit, DeployEvent};
+use dialoguer::FuzzySelect;
+use std::io::IsTerminal;
+
+/// Picks the target hosts: every host with `--all`, those matching the
+/// `--host` name or glob, the one chosen through the interactive picker, or
+/// the only configured host. The picker is used when `--select-host` is given
+/// or no `--host` was passed on a TTY. `--role` first keeps only the hosts
+/// with that role, all of which are selected without a `--host`. When several
+/// hosts may be selected the resolved set is printed.
+pub fn select_hosts(hosts: Vec<Host>, cli: &Cli) -> Result<Vec<Host>> {
+    if hosts.is_empty() {
+        return Err(DeployError::Config(
+            "No hosts found on config file".to_string(),
+        ));
+    }
+
+    let hosts = match &cli.role {
+        Some(role) => {
+            let with_role: Vec<Host> = hosts
+                .into_iter()
+                .filter(|h| h.roles().contains(role))
+                .collect();
+            if with_role.is_empty() {
+                return Err(DeployError::Config(format!(
+                    "No hosts with role `{}` found on config file",
+                    role
+                )));
+            }
+            with_role
+        }
+        None => hosts,
+    };
+
+    let selected = match &cli.host {
+        _ if cli.all => hosts,
+        None if cli.role.is_some() && !cli.select_host => hosts,
+        Some(pattern) if is_glob(pattern) => {
+            let matching: Vec<Host> = hosts
+                .into_iter()
+                .filter(|h| glob_match(pattern, h.name()))
+                .collect();
+            if matching.is_empty() {
+                return Err(DeployError::Config(format!(
+                    "No hosts matching `{}` found on config file",
+                    pattern
+                )));
+            }
+            matching
+        }
+        _ => return select_host(hosts, cli).map(|host| vec![host]),
+    };
+
+    let names: Vec<&str> = selected.iter().map(Host::name).collect();
+    emit(DeployEvent::HostsSelected { hosts: &names });
+
+    Ok(selected)
+}
+
+fn select_host(mut hosts: Vec<Host>, cli: &Cli) -> Result<Host> {
+    if let Some(name) = &cli.host {
+        let index = hosts.iter().position(|h| h.name() == name).ok_or_else(|| {
+            DeployError::Config(format!("Host `{}` not found on config file", name))
+        })?;
+        return Ok(hosts.swap_remove(index));
+    }
+
+    let interactive = std::io::stdout().is_terminal() && std::io::stdin().is_terminal();
+    if cli.select_host && !interactive {
+        return Err(DeployError::Config(
+            "`--select-host` requires an interactive terminal".to_string(),
+        ));
+    }
+
+    if cli.select_host || (interactive && hosts.len() > 1) {
+        let labels: Vec<String> = hosts.iter().map(Host::label).collect();
+        let index = FuzzySelect::new()
+            .with_prompt("Select a host")
+            .items(&labels)
+            .default(0)
+            .interact()
+            .map_err(|err| DeployError::Other(format!("Error selecting host: {}", err)))?;
+        return Ok(hosts.swap_remove(index));
+    }
+
+    if hosts.len() > 1 {
+        return Err(DeployError::Config(
+            "Multiple hosts found on config file, use `--host <name>` to pick one".to_string(),
+        ));
+    }
+    Ok(hosts.remove(0))
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Matches `name` against a glob where `*` matches any run of characters and
+/// `?` any single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn hosts(names: &[&str]) -> Vec<Host> {
+        names
+            .iter()
+            .map(|name| {
+                serde_json::from_value(serde_json::json!({ "name": name, "transport": "local" }))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn selected(names: &[&str], args: &[&str]) -> Result<Vec<String>> {
+        let cli = Cli::parse_from(["ec2-deploy"].iter().chain(args));
+        let selected = select_hosts(hosts(names), &cli)?;
+        Ok(selected.iter().map(|h| h.name().to_string()).collect())
+    }
+
+    #[test]
+    fn star_matches_any_run() {
+        assert!(glob_match("web-*", "web-1"));
+        assert!(glob_match("web-*", "web-"));
+        assert!(glob_match("*-prod", "api-prod"));
+        assert!(glob_match("w*b*1", "web-eu-1"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn question_mark_matches_one_char() {
+        assert!(glob_match("web-?", "web-1"));
+        assert!(!glob_match("web-?", "web-"));
+        assert!(!glob_match("web-?", "web-12"));
+    }
+
+    #[test]
+    fn no_match() {
+        assert!(!glob_match("web-*", "api-1"));
+        assert!(!glob_match("*-prod", "api-staging"));
+        assert!(!glob_match("web", "web-1"));
+    }
+
+    #[test]
+    fn exact_name() {
+        assert!(glob_match("web-1", "web-1"));
+        assert!(!glob_match("web-1", "web-2"));
+    }
+
+    #[test]
+    fn glob_selects_matching_hosts() {
+        let names = selected(&["web-1", "web-2", "api-1"], &["--host", "web-*"]).unwrap();
+        assert_eq!(names, ["web-1", "web-2"]);
+    }
+
+    #[test]
+    fn glob_without_matches_errors() {
+        let err = selected(&["web-1", "api-1"], &["--host", "db-*"]).unwrap_err();
+        assert!(err.to_string().contains("No hosts matching `db-*`"));
+    }
+
+    #[test]
+    fn exact_host_selects_one() {
+        let names = selected(&["web-1", "web-2"], &["--host", "web-2"]).unwrap();
+        assert_eq!(names, ["web-2"]);
+    }
+}
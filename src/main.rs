@@ -1,270 +1,2138 @@
-use openssh::{KnownHosts, OwningCommand, Session, SessionBuilder, Stdio};
-use openssh_sftp_client::metadata::Permissions;
-use openssh_sftp_client::Sftp;
-use serde::Deserialize;
-use serde_json::from_slice;
-use std::io::Write;
-use std::{env::current_dir, fs::read, fs::File, path::Path, process::Command};
-use zip::{write::SimpleFileOptions, ZipWriter};
+mod archive;
+mod audit;
+mod changes;
+mod clean;
+mod cleanup;
+mod cli;
+mod config;
+mod delta;
+mod doctor;
+mod download;
+mod error;
+mod events;
+mod history;
+mod hosts;
+mod manifest;
+mod marker;
+mod output;
+mod plan;
+mod print_config;
+mod remote;
+mod rollback;
+mod schema;
+mod signing;
+mod ssh;
+mod ssm;
+mod step;
+mod sticky;
+mod transfer;
+mod upload;
+mod vars;
+mod verify;
+mod watch;
 
-#[derive(Debug, Deserialize)]
-struct App {
-    name: String,
-    host_path: String,
-    build_output_file: String,
-    build_command: String,
-    artifacts: Vec<String>,
-    entrypoint: Option<String>,
+use archive::{build_archive, collect_entries, git_archive, ArchiveEntry, ArchiveOptions};
+use changes::ChangeState;
+use clap::Parser;
+use cleanup::{TempDir, TempFile};
+use cli::{Cli, Commands};
+use config::{
+    config_dir, load_hosts, App, BuildCommand, GlobalConfig, Host, OnFailure, Overwrite, Platform,
+    Source, Strategy, Transport, STDIN_PATH,
+};
+use delta::Delta;
+use dialoguer::Confirm;
+use download::download_artifacts;
+use error::{DeployError, Result};
+use events::{emit, DeployEvent};
+use manifest::Manifest;
+use openssh::{Session, SessionBuilder};
+use regex::Regex;
+use remote::{
+    as_user, powershell, ps_quote, stream_command, unwrap_command_stderr, with_env, Remote,
+};
+use ssh::SshOverrides;
+use std::collections::BTreeMap;
+use std::env::{current_dir, set_current_dir};
+use std::io::IsTerminal;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tokio::signal::ctrl_c;
+use tracing::{debug, info_span, instrument, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use transfer::RemoteFs;
+use vars::Vars;
+
+/// Wait between attempts of a build command with `build_retries`.
+const BUILD_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    output::init(cli.no_color);
+    events::on_event(output::event);
+    init_tracing(cli.verbose);
+
+    match run(&cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            output::failure(&err);
+            ExitCode::from(err.exit_code())
+        }
+    }
 }
 
-impl App {
-    pub fn build_output_file(&self) -> &String {
-        &self.build_output_file
+async fn run(cli: &Cli) -> Result<()> {
+    let global = GlobalConfig::load()?;
+
+    let stdin = Path::new(STDIN_PATH);
+    if cli.config.as_deref() == Some(stdin) && cli.deploy_file.as_deref() == Some(stdin) {
+        return Err(DeployError::Config(
+            "Only one of `--config` and `--deploy-file` can be read from stdin".to_string(),
+        ));
+    }
+
+    let vars = Vars::load(&cli.env_files)?;
+
+    if cli.step.is_some() && cli.command.is_some() {
+        return Err(DeployError::Config(
+            "`--step` only applies to deploys, not to subcommands".to_string(),
+        ));
+    }
+    match &cli.command {
+        Some(Commands::Doctor) => return doctor::doctor(cli, &global, &vars).await,
+        Some(Commands::Apply { plan }) => return plan::apply(cli, plan).await,
+        Some(Commands::Schema { file }) => return schema::schema(*file),
+        Some(Commands::History { app, limit }) => {
+            return history::history(cli.host.as_deref(), app.as_deref(), *limit)
+        }
+        _ => {}
+    }
+
+    let hosts = configured_hosts(cli, &vars)?;
+
+    let mut hosts = hosts::select_hosts(hosts, cli)?;
+    for host in hosts.iter_mut() {
+        host.apply_defaults(&global);
     }
-    pub fn host_path(&self) -> &String {
-        &self.host_path
+
+    if !cli.apps.is_empty() {
+        if cli.command.is_some() {
+            return Err(DeployError::Config(
+                "`--app` only applies to deploys, not to subcommands".to_string(),
+            ));
+        }
+        return deploy_apps(cli, &vars, &global, &hosts).await;
     }
-    pub fn name(&self) -> &String {
-        &self.name
+
+    let invoked_from = current_dir()
+        .map_err(|err| DeployError::Other(format!("Error reading current directory: {}", err)))?;
+    let (app, cwd) = load_app(cli, &deploy_file_path(cli), &vars, &global)?;
+
+    match &cli.command {
+        None => deploy_or_step(cli, &app, &hosts).await,
+        Some(Commands::Watch { debounce }) => {
+            watch::watch(cli, &app, &hosts, Duration::from_millis(*debounce)).await
+        }
+        Some(Commands::PrintConfig) => print_config::print_config(&app, &hosts),
+        Some(Commands::Clean { yes }) => clean::clean(&app, &hosts, *yes).await,
+        Some(Commands::Audit) => audit::audit(cli, &app, &hosts).await,
+        Some(Commands::Plan { output }) => {
+            plan::plan(cli, app, hosts, cwd, &invoked_from.join(output))
+        }
+        Some(
+            Commands::Doctor
+            | Commands::Apply { .. }
+            | Commands::Schema { .. }
+            | Commands::History { .. },
+        ) => {
+            unreachable!("handled before loading the config")
+        }
     }
-    pub fn artifacts(&self) -> &Vec<String> {
-        &self.artifacts
+}
+
+/// Reads the deploy file at `deploy_file`, applying the command line
+/// overrides, and checks it. The current directory is changed to the deploy
+/// file's, which is returned along with the app.
+fn load_app(
+    cli: &Cli,
+    deploy_file: &Path,
+    vars: &Vars,
+    global: &GlobalConfig,
+) -> Result<(App, PathBuf)> {
+    let mut app = App::load(deploy_file, vars, cli.profile.as_deref())?;
+    app.apply_defaults(global);
+    if let Some(path) = &cli.target_path {
+        check_target_path(path)?;
+        app = app.with_host_path(path.clone());
     }
-    pub fn entrypoint(&self) -> &Option<String> {
-        &self.entrypoint
+
+    enter_deploy_dir(deploy_file)?;
+    let cwd = current_dir()
+        .map_err(|err| DeployError::Other(format!("Error reading current directory: {}", err)))?;
+
+    if !cli.only_artifacts.is_empty() {
+        app.retain_artifacts(&cli.only_artifacts)?;
     }
+    check_app(cli, &mut app, &cwd)?;
+
+    Ok((app, cwd))
 }
 
-#[derive(Debug, Deserialize)]
-struct Host {
-    // name: String,
-    key_path: String,
-    user: String,
-    host: String,
-    port: u16,
+/// Resolves the restart strategy and checks the rest of the app's settings,
+/// and that its artifacts are under `cwd` unless `--allow-external`.
+fn check_app(cli: &Cli, app: &mut App, cwd: &Path) -> Result<()> {
+    app.resolve_strategy()?;
+    app.check_compression_level()?;
+    app.check_source()?;
+    app.check_remote_archive_name()?;
+    app.host_path_mode()?;
+    app.raw_mode()?;
+
+    if !cli.allow_external {
+        for artifact in app.artifacts().iter().filter(|a| !a.is_url()) {
+            validate_artifact_path(cwd, artifact.src()).map_err(|err| {
+                DeployError::Config(format!(
+                    "{}\nUse `--allow-external` to include it anyway",
+                    err
+                ))
+            })?;
+        }
+    }
+    Ok(())
 }
 
-impl Host {
-    pub fn to_url(&self) -> String {
-        format!("ssh://{}@{}:{}", self.user, self.host, self.port)
+/// Deploys the app, or runs only its `--step`.
+async fn deploy_or_step(cli: &Cli, app: &App, hosts: &[Host]) -> Result<()> {
+    match cli.step {
+        Some(step) => step::run(cli, app, hosts, step).await,
+        None => deploy(cli, app, hosts).await,
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let hosts_config_path = format!("{}/ec2-deploy/hosts.json", env!("HOME"));
+/// An `--app` deploy file's app, built and zipped, on its way to the hosts.
+struct AppDeploy {
+    /// Position of the deploy file among the `--app` ones.
+    index: usize,
+    /// The deploy file's directory, which the app's paths are relative to.
+    dir: PathBuf,
+    prepared: PreparedDeploy,
+    failed: bool,
+}
+
+/// Deploys the app of each `--app` deploy file to `hosts`, carrying on past
+/// the ones that fail, then reports how each went. Every app is built and
+/// zipped first, then each host gets all of them over a single session. With
+/// `--step`, each app runs its step on its own instead.
+async fn deploy_apps(cli: &Cli, vars: &Vars, global: &GlobalConfig, hosts: &[Host]) -> Result<()> {
+    // The deploy file paths are relative to where ec2-deploy was run, and
+    // loading each app moves into its directory.
+    let invoked_from = current_dir()
+        .map_err(|err| DeployError::Other(format!("Error reading current directory: {}", err)))?;
+
+    // The name of each app once it's deployed, `None` until then.
+    let mut results: Vec<(PathBuf, Option<String>)> = Vec::new();
+    let mut deploys = Vec::new();
+    for path in &cli.apps {
+        let path = invoked_from.join(path);
+        let (app, dir) = match load_app(cli, &path, vars, global) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                emit(DeployEvent::AppFailed { error: &error });
+                results.push((path, None));
+                continue;
+            }
+        };
+        let prepared = match cli.step {
+            Some(step) => step::run(cli, &app, hosts, step).await.map(|()| None),
+            None => {
+                prepare_deploy(cli, &app)
+                    .instrument(info_span!("deploy", app = app.name().as_str()))
+                    .await
+            }
+        };
+        match prepared {
+            Ok(Some(prepared)) => {
+                deploys.push(AppDeploy {
+                    index: results.len(),
+                    dir,
+                    prepared,
+                    failed: false,
+                });
+                results.push((path, None));
+            }
+            Ok(None) => results.push((path, Some(app.name().clone()))),
+            Err(error) => {
+                emit(DeployEvent::AppFailed { error: &error });
+                results.push((path, None));
+            }
+        }
+    }
 
-    let config_file = read(hosts_config_path).expect("Error opening hosts config file");
-    let hosts: Vec<Host> =
-        from_slice(config_file.as_slice()).expect("Error parsing hosts config file");
+    for (i, host) in hosts.iter().enumerate() {
+        deploy_apps_to_host(cli, host, &hosts[i + 1..], &mut deploys)
+            .instrument(info_span!("host", host = host.name()))
+            .await;
+    }
+    for deploy in deploys.into_iter().filter(|deploy| !deploy.failed) {
+        let name = deploy.prepared.app.name().clone();
+        match enter_dir(&deploy.dir).and_then(|()| deploy.prepared.finish()) {
+            Ok(()) => results[deploy.index].1 = Some(name),
+            Err(error) => emit(DeployEvent::AppFailed { error: &error }),
+        }
+    }
 
-    // TODO: from cli
-    let first_host = hosts.first().expect("No hosts found on config file");
+    let apps: Vec<_> = results
+        .iter()
+        .map(|(path, name)| (path.as_path(), name.as_deref()))
+        .collect();
+    emit(DeployEvent::AppsFinished { apps: &apps });
+    let failed = apps.iter().filter(|(_, name)| name.is_none()).count();
+    if failed > 0 {
+        return Err(DeployError::Other(format!(
+            "{} of {} app(s) failed to deploy",
+            failed,
+            results.len()
+        )));
+    }
+    Ok(())
+}
 
-    let session = SessionBuilder::default()
-        .keyfile(Path::new(&first_host.key_path))
-        .known_hosts_check(KnownHosts::Strict)
-        .connect(first_host.to_url())
-        .await
-        .expect("Error");
+/// Deploys each of `deploys` that hasn't failed yet to `host`, one after the
+/// other over the same session. When the connection fails or drops, a fresh
+/// session is opened and the app retried, up to `deploy_retries` times. An
+/// app failing is marked as such and listed as skipped on the `later` hosts.
+async fn deploy_apps_to_host(cli: &Cli, host: &Host, later: &[Host], deploys: &mut [AppDeploy]) {
+    let mut pending: Vec<&mut AppDeploy> =
+        deploys.iter_mut().filter(|deploy| !deploy.failed).collect();
+    if pending.is_empty() {
+        return;
+    }
+    let mut started = Instant::now();
+    let ssh = match SshOverrides::prepare(host) {
+        Ok(ssh) => ssh,
+        Err(err) => return fail_pending(&mut pending, host, later, started, err),
+    };
 
-    let mut child = session
-        .subsystem("sftp")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .await
-        .expect("Enable to launch SFTP subsystem");
+    let mut next = 0;
+    let mut attempt = 0;
+    while next < pending.len() {
+        let session = match open_session_or_interrupt(host, ssh.as_ref()).await {
+            Ok(session) => session,
+            Err(err @ DeployError::Connection(_)) if attempt < host.deploy_retries() => {
+                attempt += 1;
+                emit(DeployEvent::DeployRetry {
+                    error: &err,
+                    attempt,
+                    retries: host.deploy_retries(),
+                });
+                continue;
+            }
+            Err(err) => return fail_pending(&mut pending[next..], host, later, started, err),
+        };
+        let remote = Remote::new(session.as_ref());
 
-    let sftp = Sftp::new(
-        child.stdin().take().unwrap(),
-        child.stdout().take().unwrap(),
-        Default::default(),
-    )
+        while let Some(deploy) = pending.get_mut(next) {
+            let app = &deploy.prepared.app;
+            let result = match enter_dir(&deploy.dir) {
+                Ok(()) => {
+                    deploy_on_session(cli, app, host, &remote)
+                        .instrument(info_span!("deploy", app = app.name().as_str()))
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            let result = match result {
+                Err(err @ DeployError::Connection(_)) if attempt < host.deploy_retries() => {
+                    attempt += 1;
+                    emit(DeployEvent::DeployRetry {
+                        error: &err,
+                        attempt,
+                        retries: host.deploy_retries(),
+                    });
+                    break;
+                }
+                result => result.map_err(|err| {
+                    err.context(format!("Deploying `{}` to `{}`", app.name(), host.name()))
+                }),
+            };
+
+            history::append(app.name(), host.name(), started.elapsed(), &result);
+            match &result {
+                Ok(()) => emit(DeployEvent::HostDeployed {
+                    app: app.name(),
+                    host: host.name(),
+                    elapsed: started.elapsed(),
+                }),
+                Err(error) => {
+                    emit(DeployEvent::AppFailed { error });
+                    for skipped in later {
+                        emit(DeployEvent::HostSkipped {
+                            host: skipped.name(),
+                        });
+                    }
+                    deploy.failed = true;
+                }
+            }
+            next += 1;
+            attempt = 0;
+            started = Instant::now();
+        }
+
+        close_session(session).await;
+        emit(DeployEvent::ConnectionClosed);
+    }
+}
+
+/// Marks every app in `pending` as failed on `host` with `err`, which kept
+/// them from being deployed there, and lists the `later` hosts as skipped.
+fn fail_pending(
+    pending: &mut [&mut AppDeploy],
+    host: &Host,
+    later: &[Host],
+    started: Instant,
+    err: DeployError,
+) {
+    let result = Err(err.context(format!("Deploying to `{}`", host.name())));
+    for deploy in pending.iter_mut() {
+        history::append(
+            deploy.prepared.app.name(),
+            host.name(),
+            started.elapsed(),
+            &result,
+        );
+        deploy.failed = true;
+    }
+    if let Err(error) = &result {
+        emit(DeployEvent::AppFailed { error });
+    }
+    for skipped in later {
+        emit(DeployEvent::HostSkipped {
+            host: skipped.name(),
+        });
+    }
+}
+
+/// The `--host-url` host, or else every host in the hosts config.
+fn configured_hosts(cli: &Cli, vars: &Vars) -> Result<Vec<Host>> {
+    match (&cli.host_url, &cli.key) {
+        (Some(url), Some(key)) => Ok(vec![Host::from_url(url, key)?]),
+        _ => load_hosts(&hosts_path(cli)?, vars),
+    }
+}
+
+fn hosts_path(cli: &Cli) -> Result<PathBuf> {
+    match &cli.config {
+        Some(path) => Ok(path.clone()),
+        None => Ok(config_dir()?.join("hosts.json")),
+    }
+}
+
+fn deploy_file_path(cli: &Cli) -> PathBuf {
+    cli.deploy_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("deploy.json"))
+}
+
+/// `--target-path` must name the same directory whatever the SSH user's
+/// working directory: an absolute unix or Windows path, or one under `~`,
+/// which is expanded on the host.
+fn check_target_path(path: &str) -> Result<()> {
+    let bytes = path.as_bytes();
+    let windows_absolute = bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'\\' | b'/');
+    if path.starts_with('/') || path == "~" || path.starts_with("~/") || windows_absolute {
+        return Ok(());
+    }
+    Err(DeployError::Config(format!(
+        "`--target-path {}` must be an absolute path or start with `~/`",
+        path
+    )))
+}
+
+/// Artifact, build output and entrypoint paths are relative to the deploy
+/// file, so the rest of the deploy runs from its directory. A deploy file
+/// piped through stdin is relative to the current directory.
+fn enter_deploy_dir(deploy_file: &Path) -> Result<()> {
+    match deploy_file.parent().filter(|d| !d.as_os_str().is_empty()) {
+        Some(dir) => enter_dir(dir),
+        None => Ok(()),
+    }
+}
+
+fn enter_dir(dir: &Path) -> Result<()> {
+    set_current_dir(dir).map_err(|err| {
+        DeployError::Config(format!(
+            "Error changing directory to `{}`: {}",
+            dir.display(),
+            err
+        ))
+    })
+}
+
+/// Logs to stderr, filtered by `RUST_LOG` or, when unset, by `--verbose`.
+fn init_tracing(verbose: bool) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(if verbose { "ec2_deploy=debug" } else { "warn" }));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(console::colors_enabled_stderr())
+        .init();
+}
+
+/// Builds and zips the app once, then deploys the archive to each host in
+/// turn, stopping at the first failure. Hosts left out by a failure are
+/// listed as skipped. Nothing runs when the app has `when_changed` paths and
+/// none changed since the last deploy that reached every host.
+async fn deploy(cli: &Cli, app: &App, hosts: &[Host]) -> Result<()> {
+    let span = info_span!("deploy", app = app.name().as_str());
+
+    async {
+        let Some(prepared) = prepare_deploy(cli, app).await? else {
+            return Ok(());
+        };
+        let app = &prepared.app;
+
+        for (i, host) in hosts.iter().enumerate() {
+            let host_started = Instant::now();
+            let result = deploy_with_retries(cli, app, host)
+                .instrument(info_span!("host", host = host.name()))
+                .await
+                .map_err(|err| err.context(format!("Deploying to `{}`", host.name())));
+            history::append(app.name(), host.name(), host_started.elapsed(), &result);
+            if let Err(err) = result {
+                for skipped in &hosts[i + 1..] {
+                    emit(DeployEvent::HostSkipped {
+                        host: skipped.name(),
+                    });
+                }
+                return Err(err);
+            }
+
+            emit(DeployEvent::HostDeployed {
+                app: app.name(),
+                host: host.name(),
+                elapsed: host_started.elapsed(),
+            });
+        }
+
+        prepared.finish()
+    }
+    .instrument(span)
     .await
-    .expect("Error starting SFTP client");
+}
 
-    let cwd = current_dir().unwrap();
-    let deploy_file = read(format!("{}/deploy.json", cwd.to_str().unwrap()))
-        .expect("Error opening `deploy.json` file on current directory");
+/// An app built and zipped, ready to deploy to any number of hosts. The
+/// archive, downloaded artifacts and saved images are removed once it's
+/// dropped.
+struct PreparedDeploy {
+    app: App,
+    /// State of the `when_changed` paths, recorded once every host has the
+    /// app.
+    changes: Option<ChangeState>,
+    _archive_dir: TempDir,
+    _downloads: Option<TempDir>,
+    _images: Option<TempFile>,
+}
 
-    let app: App = from_slice(deploy_file.as_slice()).expect("Error parsing `deploy.json` file");
+impl PreparedDeploy {
+    /// Records the `when_changed` state, so the app isn't deployed again
+    /// until one of its paths changes.
+    fn finish(self) -> Result<()> {
+        match self.changes {
+            Some(current) => current.write(&ChangeState::path_for(self.app.archive_name())),
+            None => Ok(()),
+        }
+    }
+}
 
-    let build_output_file_path = Path::new(app.build_output_file().as_str());
-    let host_output_path = format!(
-        "{}/{}",
-        app.host_path(),
-        build_output_file_path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-    );
+/// Builds, zips and signs `app` for deploying, or returns `None` when it has
+/// `when_changed` paths and none changed since the last deploy that reached
+/// every host.
+async fn prepare_deploy(cli: &Cli, app: &App) -> Result<Option<PreparedDeploy>> {
+    let changes = if app.when_changed().is_empty() {
+        None
+    } else {
+        Some(ChangeState::current(app.when_changed())?)
+    };
+    if let Some(current) = &changes {
+        let state_path = ChangeState::path_for(app.archive_name());
+        if cli.force {
+            warn!("--force: deploying without checking `when_changed` paths");
+        } else if ChangeState::load(&state_path).as_ref() == Some(current) {
+            emit(DeployEvent::Unchanged);
+            return Ok(None);
+        }
+    }
 
-    println!("Deploying app: {}", app.name());
+    emit(DeployEvent::Started { app: app.name() });
 
-    let mut build_command: Vec<&str> = app.build_command.split_whitespace().collect();
-    match Command::new(build_command.remove(0))
-        .args(build_command)
-        .output()
-    {
-        Ok(out) => {
-            if out.stderr.len() > 0 {
-                panic!(
-                    "Got build error:\n{}",
-                    String::from_utf8(out.stderr.to_vec()).unwrap()
-                );
+    let archive_dir = TempDir::new("archive").map_err(|err| {
+        DeployError::Build(format!("Error creating the archive's directory: {}", err))
+    })?;
+    let PreparedApp {
+        app,
+        _downloads: downloads,
+    } = prepare_artifacts(cli, &app.with_archive_dir(archive_dir.path())).await?;
+    let (app, images) = if app.compose_build_local() {
+        let images = build_compose_images(&app)?;
+        let app = app.with_artifact(images.path().display().to_string());
+        (app, Some(images))
+    } else {
+        (app, None)
+    };
+    if cli.interactive || cli.verbose {
+        confirm_entries(&app, cli.force, cli.allow_external)?;
+    }
+    if cli.raw {
+        raw_entry(&app, cli.allow_external)?;
+        if app.sign_key().is_some() {
+            warn!("--raw: the file isn't signed, `sign_key` only applies to the archive");
+        }
+    } else {
+        zip_artifacts(&app, cli)?;
+        if let Some(key) = app.sign_key() {
+            signing::sign(&app, key)?;
+        }
+    }
+
+    Ok(Some(PreparedDeploy {
+        app,
+        changes,
+        _archive_dir: archive_dir,
+        _downloads: downloads,
+        _images: images,
+    }))
+}
+
+/// Deploys to `host`, starting over on a fresh session up to `deploy_retries`
+/// times when the connection fails or drops. The archive only replaces the
+/// previous one once fully uploaded, so a retry never extracts a truncated
+/// archive.
+async fn deploy_with_retries(cli: &Cli, app: &App, host: &Host) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match deploy_to_host(cli, app, host).await {
+            Err(err @ DeployError::Connection(_)) if attempt < host.deploy_retries() => {
+                attempt += 1;
+                emit(DeployEvent::DeployRetry {
+                    error: &err,
+                    attempt,
+                    retries: host.deploy_retries(),
+                });
             }
-            println!("Build ran successfully!");
+            result => return result,
         }
-        Err(err) => {
-            panic!("Error running build command:\n{}", err);
+    }
+}
+
+/// Runs the remote phases against a single host. The SFTP client and SSH
+/// session are always closed before returning, whatever failed.
+async fn deploy_to_host(cli: &Cli, app: &App, host: &Host) -> Result<()> {
+    let ssh = SshOverrides::prepare(host)?;
+    let session = open_session_or_interrupt(host, ssh.as_ref()).await?;
+
+    let remote = Remote::new(session.as_ref());
+    let result = deploy_on_session(cli, app, host, &remote).await;
+
+    close_session(session).await;
+    emit(DeployEvent::ConnectionClosed);
+
+    result
+}
+
+/// Opens the session to `host` as [`open_session`] does, giving up when
+/// Ctrl-C is pressed first.
+async fn open_session_or_interrupt(
+    host: &Host,
+    ssh: Option<&SshOverrides>,
+) -> Result<Option<Session>> {
+    tokio::select! {
+        session = open_session(host, ssh) => session,
+        _ = ctrl_c() => Err(interrupted()),
+    }
+}
+
+/// Opens the SFTP client on an open session and deploys through it, closing
+/// it again whatever the outcome.
+async fn deploy_on_session(cli: &Cli, app: &App, host: &Host, remote: &Remote<'_>) -> Result<()> {
+    let fs = RemoteFs::open(*remote, host).await?;
+    let result = deploy_with_fs(cli, app, host, remote, &fs).await;
+    fs.close().await;
+    result
+}
+
+/// Uploads, extracts and restarts the app on the host. On failure or Ctrl-C
+/// any partially uploaded archive is removed.
+async fn deploy_with_fs(
+    cli: &Cli,
+    app: &App,
+    host: &Host,
+    remote: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+) -> Result<()> {
+    let expanded_app;
+    let app = match with_home_expanded(remote, app, host.platform()).await? {
+        Some(app) => {
+            expanded_app = app;
+            &expanded_app
+        }
+        None => app,
+    };
+
+    let (local, host_output_path) = if cli.raw {
+        let entry = raw_entry(app, cli.allow_external)?;
+        (
+            entry.source,
+            host.platform().join(app.host_path(), &entry.name),
+        )
+    } else {
+        (
+            PathBuf::from(app.archive_path()),
+            host_output_path(app, host.platform()),
+        )
+    };
+
+    let result = tokio::select! {
+        result = deploy_remote(cli, app, host, remote, fs, &local, &host_output_path) => result,
+        _ = ctrl_c() => Err(interrupted()),
+    };
+
+    // A remote command or SFTP failure on a dead session is the connection
+    // dropping rather than the command itself failing.
+    let result = match result {
+        Err(DeployError::Remote(message)) if remote.check().await.is_err() => {
+            Err(DeployError::Connection(message).context("Connection to host lost"))
+        }
+        result => result,
+    };
+
+    if result.is_err() {
+        let partial = partial_path(&host_output_path);
+        if let Err(err) = fs.remove(&partial).await {
+            debug!(path = %partial, %err, "no partial upload removed");
         }
     }
 
-    let build_file = File::create(app.build_output_file())
-        .expect(format!("Error creating output file `{}`", app.build_output_file()).as_str());
+    marker::write(fs, app, host.platform(), &local, &result).await;
+    if result.is_ok() && cli.only_upload_changed_entries {
+        if let Err(err) = delta::record(app, host) {
+            warn!(%err, "the next deploy will upload the full archive");
+        }
+    }
+    result
+}
 
-    let mut zip_build = ZipWriter::new(&build_file);
+/// Closes the SSH session, warning when it doesn't shut down cleanly, which
+/// may leave its `ssh` control master process behind.
+async fn close_session(session: Option<Session>) {
+    if let Some(session) = session {
+        if let Err(err) = session.close().await {
+            warn!(%err, "error closing the SSH session");
+        }
+    }
+}
 
-    for path_str in app.artifacts() {
-        let path = Path::new(path_str.as_str());
-        let name = path
-            .file_name()
-            .expect(format!("Error getting artifact path `{}`", path_str).as_str())
-            .to_str()
-            .unwrap();
+/// Copy of `app` with a `~` in its `host_path` expanded on the host, or `None`
+/// when there is none, so SFTP and remote commands target the same path.
+async fn with_home_expanded(
+    session: &Remote<'_>,
+    app: &App,
+    platform: Platform,
+) -> Result<Option<App>> {
+    let host_path = remote::expand_home(session, platform, app.host_path())
+        .await
+        .map_err(DeployError::Remote)?;
+    Ok(host_path.map(|host_path| app.with_host_path(host_path)))
+}
+
+async fn deploy_remote(
+    cli: &Cli,
+    app: &App,
+    host: &Host,
+    session: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    local: &Path,
+    host_output_path: &str,
+) -> Result<()> {
+    let delta = if cli.only_upload_changed_entries && !cli.full {
+        Delta::prepare(app, host)?
+    } else {
+        None
+    };
+    if let Some(delta) = &delta {
+        emit(DeployEvent::DeltaPrepared {
+            changed: delta.changed,
+            removed: delta.removed.len(),
+        });
+    }
+
+    check_host_path(fs, app).await?;
+    if cli.force {
+        warn!("--force: not checking sticky files for local changes");
+    } else if !cli.overwrite_config {
+        sticky::check_sticky_files(fs, app).await?;
+    }
+
+    let platform = host.platform();
+    let backup = match app.on_failure() {
+        OnFailure::Rollback if platform == Platform::Windows => {
+            warn!("`\"on_failure\": \"rollback\"` is only supported on unix hosts");
+            None
+        }
+        OnFailure::Rollback => rollback::backup(session, app).await?,
+        OnFailure::Leave => None,
+    };
+
+    // Whether the app may already run the new files, and so needs
+    // restarting again after a rollback.
+    let mut restarted = false;
+    let result = async {
+        match &delta {
+            // An archive without files would only fail to unzip.
+            Some(delta) if delta.changed == 0 => debug!("no changed entries to upload"),
+            _ => {
+                let local = delta.as_ref().map_or(local, |delta| delta.archive.path());
+                upload(session, fs, cli, app, host, local, host_output_path)
+                    .await
+                    .map_err(|err| err.context(format!("Uploading `{}`", host_output_path)))?;
+                // A delta archive doesn't match the full archive's signature.
+                if app.sign_key().is_some() && delta.is_none() && !cli.raw {
+                    signing::upload_signature(session, fs, app, platform, host_output_path).await?;
+                }
+                if cli.raw {
+                    set_raw_mode(fs, app, platform, host_output_path).await?;
+                } else {
+                    extract(session, app, platform, host_output_path)
+                        .await
+                        .map_err(|err| err.context(format!("Extracting `{}`", host_output_path)))?;
+                }
+            }
+        }
+        if let Some(delta) = &delta {
+            remove_entries(session, app, platform, &delta.removed).await?;
+        }
+        if app.verify_file_count() && !cli.raw {
+            verify::verify_file_count(session, app, platform)
+                .await
+                .map_err(|err| err.context("Verifying the extracted files"))?;
+        }
+        after_extract(session, app, host.env())
+            .await
+            .map_err(|err| err.context("Running the `after_extract` hooks"))?;
+        restarted = true;
+        restart(session, fs, app, host, cli.force)
+            .await
+            .map_err(|err| err.context("Restarting the app"))?;
+        warm_up(session, app, platform).await;
+        smoke_test(app, host, cli.shell_build).await
+    }
+    .await;
+
+    let Some(backup) = backup else {
+        return result;
+    };
+    if result.is_ok() {
+        rollback::discard(fs, backup).await;
+        return result;
+    }
+    if let Err(err) = rollback::restore(session, app, backup).await {
+        warn!(%err, "rollback failed, the host is left as the deploy failed");
+        return result;
+    }
+    emit(DeployEvent::RolledBack {
+        path: app.host_path(),
+    });
+    if restarted {
+        if let Err(err) = restart(session, fs, app, host, cli.force).await {
+            warn!(%err, "error restarting the app after the rollback");
+        }
+    }
+    result
+}
+
+/// Fails early when `host_path` exists but isn't a directory, which would
+/// otherwise only surface as an `unzip` error after the upload.
+async fn check_host_path(fs: &RemoteFs<'_>, app: &App) -> Result<()> {
+    match fs.is_file(app.host_path()).await {
+        Ok(true) => Err(DeployError::Config(format!(
+            "host_path `{}` exists and is not a directory",
+            app.host_path()
+        ))),
+        Ok(false) => Ok(()),
+        Err(err) => Err(DeployError::Remote(format!(
+            "Error reading host_path `{}` on host: {}",
+            app.host_path(),
+            err
+        ))),
+    }
+}
 
-        let options =
-            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+fn interrupted() -> DeployError {
+    DeployError::Other("Deploy interrupted".to_string())
+}
 
-        if path.is_file() {
-            zip_build
-                .start_file(name, options)
-                .expect(format!("Error including artifact `{}`", &path_str).as_str());
+/// Sets `host_path` to `mode` and reads it back, since `mkdir -p` leaves an
+/// existing directory alone and a new one to the umask. Windows has no mode
+/// bits, so there it's only logged.
+async fn set_host_path_mode(
+    fs: &RemoteFs<'_>,
+    app: &App,
+    platform: Platform,
+    mode: u16,
+) -> Result<()> {
+    if platform == Platform::Windows {
+        warn!("`host_path_mode` has no effect on Windows hosts");
+        return Ok(());
+    }
 
-            let content = read(path_str.as_str())
-                .expect(format!("Error reading artifact content `{}`", &path_str).as_str());
+    fs.set_mode(app.host_path(), mode).await.map_err(|err| {
+        DeployError::Remote(format!(
+            "Error setting mode {:o} on `{}`: {}",
+            mode,
+            app.host_path(),
+            err
+        ))
+    })?;
+    debug!(path = %app.host_path(), mode = format!("{:o}", mode), "host_path mode set");
 
-            zip_build
-                .write_all(&content)
-                .expect(format!("Error writing artifact content `{}`", path_str).as_str());
+    Ok(())
+}
+
+/// The single file artifact `--raw` uploads in place of the archive, with
+/// the name it lands as in `host_path`.
+fn raw_entry(app: &App, allow_external: bool) -> Result<ArchiveEntry> {
+    let mut entries = match app.artifacts().as_slice() {
+        [artifact] if Path::new(artifact.src()).is_file() => collect_entries(
+            app.artifacts(),
+            app.max_artifact_size(),
+            app.max_depth(),
+            allow_external,
+        )?,
+        _ => {
+            return Err(DeployError::Config(
+                "`--raw` needs exactly one artifact, and it must be a file".to_string(),
+            ))
         }
+    };
+    Ok(entries.remove(0))
+}
 
-        if path.is_dir() {
-            for e in path.read_dir().unwrap().into_iter() {
-                let entry = e.expect("Error reading artifact dir entry");
+/// Gives the file uploaded with `--raw` its `raw_mode`, if any.
+async fn set_raw_mode(fs: &RemoteFs<'_>, app: &App, platform: Platform, path: &str) -> Result<()> {
+    let Some(mode) = app.raw_mode()? else {
+        return Ok(());
+    };
+    if platform == Platform::Windows {
+        warn!("`raw_mode` has no effect on Windows hosts");
+        return Ok(());
+    }
+
+    fs.set_mode(path, mode).await.map_err(|err| {
+        DeployError::Remote(format!(
+            "Error setting mode {:o} on `{}`: {}",
+            mode, path, err
+        ))
+    })
+}
+
+/// Path of the archive on the host.
+fn host_output_path(app: &App, platform: Platform) -> String {
+    platform.join(app.host_path(), app.remote_archive_name())
+}
+
+/// Path the archive is uploaded to before being renamed into place.
+fn partial_path(host_output_path: &str) -> String {
+    format!("{}.partial", host_output_path)
+}
 
-                zip_build
-                    .start_file(entry.file_name().into_string().unwrap(), options)
-                    .expect(format!("Error including artifact `{}`", &path_str).as_str());
+/// SSH session to `host`, `None` for a `"transport": "local"` host, whose
+/// remote steps run on this machine instead.
+async fn open_session(host: &Host, ssh: Option<&SshOverrides>) -> Result<Option<Session>> {
+    match host.transport() {
+        Transport::Ssh => connect(host, ssh).await.map(Some),
+        Transport::Local => Ok(None),
+    }
+}
 
-                let content = read(entry.path()).expect(
-                    format!(
-                        "Error reading artifact content `{}`",
-                        entry.path().to_str().unwrap()
-                    )
-                    .as_str(),
-                );
+#[instrument(name = "connect", skip_all)]
+async fn connect(host: &Host, ssh: Option<&SshOverrides>) -> Result<Session> {
+    let mut builder = SessionBuilder::default();
+    builder
+        .keyfile(Path::new(host.key_path()))
+        .known_hosts_check(host.known_hosts().into());
+    if let Some(interval) = host.keepalive() {
+        builder.server_alive_interval(interval);
+    }
+    if host.ssh_compression() {
+        builder.compression(true);
+    }
+    if let Some(ssh) = ssh {
+        ssh.apply(&mut builder);
+    }
 
-                zip_build
-                    .write_all(&content)
-                    .expect(format!("Error writing artifact content `{}`", path_str).as_str());
+    let mut attempt = 0;
+    loop {
+        let launched = match ssh {
+            Some(ssh) => ssh.launch(host).await,
+            None => None,
+        };
+        let result = match launched {
+            Some(result) => result,
+            None => builder.connect(host.to_url()).await,
+        };
+        match result {
+            Ok(session) => return Ok(session),
+            // Retrying can't make a mismatched key match.
+            Err(err)
+                if host.host_key().is_some()
+                    && err.to_string().contains("Host key verification failed") =>
+            {
+                return Err(DeployError::Config(format!(
+                    "Host key of `{}` doesn't match its pinned `host_key`: {}",
+                    host.name(),
+                    err
+                )))
+            }
+            Err(err) if attempt < host.retries() => {
+                attempt += 1;
+                emit(DeployEvent::ConnectRetry {
+                    error: &err,
+                    attempt,
+                    retries: host.retries(),
+                });
+            }
+            Err(err) => {
+                return Err(DeployError::Connection(format!(
+                    "Error connecting to host: {}",
+                    err
+                )))
             }
         }
     }
+}
+
+/// Runs the build command, returning the path `build_output_from_stdout`
+/// captured from its output when set.
+#[instrument(skip_all)]
+async fn build(app: &App, shell_build: bool) -> Result<Option<String>> {
+    if app.build_command().is_empty() {
+        emit(DeployEvent::BuildSkipped);
+        return Ok(None);
+    }
+
+    let build_command = if let Some(image) = app.build_image() {
+        let cwd = current_dir().map_err(|err| {
+            DeployError::Other(format!("Error reading current directory: {}", err))
+        })?;
+        let mut command = Command::new("docker");
+        command
+            .args(["run", "--rm", "--quiet", "-v"])
+            .arg(format!("{}:/app", cwd.display()))
+            .args(["-w", "/app", image, "sh", "-c"])
+            .arg(app.build_command().shell_line());
+        command
+    } else {
+        local_command(app.build_command(), shell_build || app.shell())?
+    };
+
+    debug!(command = ?build_command, "running build command");
+    let mut build_command = tokio::process::Command::from(build_command);
 
-    zip_build
-        .finish()
-        .expect("Error writing to build output file");
+    let mut attempt = 0;
+    let out = loop {
+        match run_build(&mut build_command).await {
+            Err(err) if attempt < app.build_retries() => {
+                attempt += 1;
+                emit(DeployEvent::BuildRetry {
+                    error: &err,
+                    attempt,
+                    retries: app.build_retries(),
+                });
+                tokio::time::sleep(BUILD_RETRY_DELAY).await;
+            }
+            result => break result?,
+        }
+    };
+    emit(DeployEvent::Built);
 
-    unwrap_command_stderr(session.command("mkdir").args(&["-p", app.host_path()]))
+    match app.build_output_from_stdout() {
+        Some(pattern) => captured_path(pattern, &out.stdout).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Runs the build command once, failing on a non-zero exit or any output on
+/// stderr.
+async fn run_build(build_command: &mut tokio::process::Command) -> Result<std::process::Output> {
+    let out = build_command
+        .output()
         .await
-        .expect("Error creating app host directory");
+        .map_err(|err| DeployError::Build(format!("Error running build command:\n{}", err)))?;
 
-    let mut fs = sftp.fs();
+    debug!(
+        status = %out.status,
+        stdout = %String::from_utf8_lossy(&out.stdout),
+        "build command finished"
+    );
+    if !out.stderr.is_empty() || !out.status.success() {
+        return Err(DeployError::Build(format!(
+            "Got build error ({}):\n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        )));
+    }
+    Ok(out)
+}
 
-    fs.write(
-        &host_output_path,
-        read(app.build_output_file()).expect("Error reading new build file content"),
-    )
-    .await
-    .expect("Error writing build file into host's fs");
+/// The app with the artifacts a deploy adds to the configured ones, all
+/// checked to exist. The downloaded URL artifacts are removed once it's
+/// dropped.
+struct PreparedApp {
+    app: App,
+    _downloads: Option<TempDir>,
+}
+
+/// Runs the build, then prepares the app's artifacts as
+/// [`with_added_artifacts`] does.
+async fn prepare_artifacts(cli: &Cli, app: &App) -> Result<PreparedApp> {
+    let built = build(app, cli.shell_build).await?;
+    with_added_artifacts(cli, app, built).await
+}
 
-    println!("Build output file written! Unzipping...");
+/// Adds the file at `built`, reported by the build, the paths listed by
+/// `artifacts_command` and the downloaded URL artifacts to the app's
+/// artifacts, then checks that they all exist.
+async fn with_added_artifacts(cli: &Cli, app: &App, built: Option<String>) -> Result<PreparedApp> {
+    let mut app = match built {
+        Some(path) => with_built_artifact(cli, app, path)?,
+        None => app.clone(),
+    };
+    if let Some(listed) = with_command_artifacts(cli, &app)? {
+        app = listed;
+    }
+    let mut downloads = None;
+    if let Some((downloaded, dir)) = download_artifacts(&app).await? {
+        app = downloaded;
+        downloads = Some(dir);
+    }
+    check_artifacts(&app)?;
+    Ok(PreparedApp {
+        app,
+        _downloads: downloads,
+    })
+}
 
-    unwrap_command_stderr(
-        session
-            .command("unzip")
-            .args(&["-o", &host_output_path.as_str()])
-            .args(&["-d", app.host_path()]),
-    )
-    .await
-    .expect("Error unzipping output file");
-
-    if app.entrypoint().is_some() {
-        let entrypoint = app.entrypoint().as_ref().unwrap();
-        println!("Found entrypoint file `{}`", entrypoint);
-
-        let host_entrypoint_path = format!("{}/{}", app.host_path(), entrypoint);
-        if !app.artifacts().into_iter().any(|a| a.eq(entrypoint)) {
-            println!("Entrypoint not fount on artifacts, uploading...");
-            fs.write(
-                &host_entrypoint_path,
-                read(entrypoint).expect("Error reading entrypoint file"),
-            )
+/// Errors when an artifact is missing once the build has run, which would
+/// otherwise just be left out of the archive.
+fn check_artifacts(app: &App) -> Result<()> {
+    if app.source() != Source::Artifacts {
+        return Ok(());
+    }
+    match app
+        .artifacts()
+        .iter()
+        .find(|artifact| !Path::new(artifact.src()).exists())
+    {
+        Some(artifact) if app.build_command().is_empty() => Err(DeployError::Build(format!(
+            "Artifact `{}` doesn't exist",
+            artifact.src()
+        ))),
+        Some(artifact) => Err(DeployError::Build(format!(
+            "Artifact `{}` doesn't exist after the build, check where the build command writes its output",
+            artifact.src()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Sends the app's warm-up requests to `warmup_url` from the host with
+/// `curl`, or `Invoke-WebRequest` on Windows. Their outcome doesn't affect
+/// the deploy, a missing `curl` being only warned about.
+async fn warm_up(session: &Remote<'_>, app: &App, platform: Platform) {
+    let Some(url) = app.warmup_url() else {
+        return;
+    };
+    for i in 0..app.warmup_requests() {
+        let mut request = match platform {
+            Platform::Unix => {
+                let mut command = session.command("curl");
+                command.args(["-s", "-o", "/dev/null", "--max-time", "30", url]);
+                command
+            }
+            Platform::Windows => session.raw_command(powershell(&format!(
+                "try {{ Invoke-WebRequest -UseBasicParsing -TimeoutSec 30 -Uri {} | Out-Null }} catch {{}}",
+                ps_quote(url)
+            ))),
+        };
+        match unwrap_command_stderr(&mut request).await {
+            Ok(out) if out.status.code() == Some(127) => {
+                warn!("`curl` not found on the host, not warming up the app");
+                return;
+            }
+            Ok(out) => debug!(request = i + 1, status = ?out.status, "warm-up request sent"),
+            Err(err) => debug!(request = i + 1, %err, "warm-up request failed"),
+        }
+    }
+    emit(DeployEvent::WarmedUp {
+        url,
+        requests: app.warmup_requests(),
+    });
+}
+
+/// Runs the app's `smoke_test`, if any, with its output passed through and
+/// `EC2_DEPLOY_HOST` set to the name of the host just deployed.
+#[instrument(skip_all)]
+async fn smoke_test(app: &App, host: &Host, shell_build: bool) -> Result<()> {
+    if app.smoke_test().is_empty() {
+        return Ok(());
+    }
+
+    let line = app.smoke_test().shell_line();
+    emit(DeployEvent::SmokeTest { command: &line });
+    let status =
+        tokio::process::Command::from(local_command(app.smoke_test(), shell_build || app.shell())?)
+            .env("EC2_DEPLOY_HOST", host.name())
+            .status()
             .await
-            .expect("Error writing entrypoint file into host's fs")
+            .map_err(|err| DeployError::Other(format!("Error running smoke test:\n{}", err)))?;
+    if !status.success() {
+        return Err(DeployError::Other(format!(
+            "Smoke test `{}` exited with {}",
+            line, status
+        )));
+    }
+    emit(DeployEvent::SmokeTestPassed);
+
+    Ok(())
+}
+
+/// `command` ready to run locally, through `sh -c` with `shell`.
+fn local_command(command: &BuildCommand, shell: bool) -> Result<Command> {
+    if shell {
+        let mut sh = Command::new("sh");
+        sh.arg("-c").arg(command.shell_line());
+        return Ok(sh);
+    }
+    let mut argv = command.argv()?;
+    let mut local = Command::new(argv.remove(0));
+    local.args(argv);
+    Ok(local)
+}
+
+/// Builds the compose images locally and saves them to the tarball shipped
+/// for `compose_build_local`, which is removed once the deploy is over.
+#[instrument(skip_all)]
+fn build_compose_images(app: &App) -> Result<TempFile> {
+    let out = Command::new("docker-compose")
+        .args(["config", "--images"])
+        .output()
+        .map_err(|err| {
+            DeployError::Build(format!("Error running `docker-compose config`: {}", err))
+        })?;
+    if !out.status.success() {
+        return Err(DeployError::Build(format!(
+            "Error listing compose images:\n{}",
+            String::from_utf8_lossy(&out.stderr)
+        )));
+    }
+    let images: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|image| !image.is_empty())
+        .map(str::to_string)
+        .collect();
+    debug!(?images, "building compose images locally");
+
+    let status = Command::new("docker-compose")
+        .arg("build")
+        .status()
+        .map_err(|err| {
+            DeployError::Build(format!("Error running `docker-compose build`: {}", err))
+        })?;
+    if !status.success() {
+        return Err(DeployError::Build(format!(
+            "`docker-compose build` exited with {}",
+            status
+        )));
+    }
+
+    let path = app.compose_images_path();
+    let saved = TempFile::new(&path);
+    let status = Command::new("docker")
+        .args(["save", "-o", &path])
+        .args(&images)
+        .status()
+        .map_err(|err| DeployError::Build(format!("Error running `docker save`: {}", err)))?;
+    if !status.success() {
+        return Err(DeployError::Build(format!(
+            "`docker save` exited with {}",
+            status
+        )));
+    }
+    emit(DeployEvent::ImagesSaved { path: &path });
+
+    Ok(saved)
+}
+
+/// Path matched by `pattern` in the build command's `stdout`.
+fn captured_path(pattern: &str, stdout: &[u8]) -> Result<String> {
+    let regex = Regex::new(pattern).map_err(|err| {
+        DeployError::Config(format!("Invalid `build_output_from_stdout` regex: {}", err))
+    })?;
+    let stdout = String::from_utf8_lossy(stdout);
+    let captures = regex.captures(&stdout).ok_or_else(|| {
+        DeployError::Build(format!(
+            "`build_output_from_stdout` regex `{}` didn't match the build command's output",
+            pattern
+        ))
+    })?;
+    let matched = captures
+        .get(1)
+        .or_else(|| captures.get(0))
+        .expect("a match always has group 0");
+    Ok(matched.as_str().trim().to_string())
+}
+
+/// Adds the file the build reported producing to the app's artifacts,
+/// holding it to the same rules as the configured ones.
+fn with_built_artifact(cli: &Cli, app: &App, path: String) -> Result<App> {
+    if !Path::new(&path).exists() {
+        return Err(DeployError::Build(format!(
+            "Build output `{}` captured from the build command's output doesn't exist",
+            path
+        )));
+    }
+    check_generated_artifact(cli, &path)?;
+    debug!(path = %path, "adding artifact captured from build output");
+    Ok(app.with_artifact(path))
+}
+
+/// Copy of `app` with the paths its `artifacts_command` lists added to the
+/// artifacts, `None` without one. Each path must exist and, like the
+/// configured artifacts, stay in the project without `--allow-external`.
+fn with_command_artifacts(cli: &Cli, app: &App) -> Result<Option<App>> {
+    if app.artifacts_command().is_empty() || app.source() == Source::Git {
+        return Ok(None);
+    }
+
+    let line = app.artifacts_command().shell_line();
+    let out = local_command(app.artifacts_command(), cli.shell_build || app.shell())?
+        .output()
+        .map_err(|err| DeployError::Build(format!("Error running artifacts command:\n{}", err)))?;
+    if !out.status.success() {
+        return Err(DeployError::Build(format!(
+            "Artifacts command `{}` exited with {}:\n{}",
+            line,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )));
+    }
+
+    let mut listed = app.clone();
+    for path in String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+    {
+        if !Path::new(path).exists() {
+            return Err(DeployError::Build(format!(
+                "Artifact `{}` listed by `{}` doesn't exist",
+                path, line
+            )));
+        }
+        check_generated_artifact(cli, path)?;
+        debug!(%path, "adding artifact listed by artifacts command");
+        listed = listed.with_artifact(path.to_string());
+    }
+    Ok(Some(listed))
+}
+
+/// Holds an artifact path produced at deploy time to the same rules as the
+/// configured ones.
+fn check_generated_artifact(cli: &Cli, path: &str) -> Result<()> {
+    if !cli.allow_external {
+        let cwd = current_dir().map_err(|err| {
+            DeployError::Other(format!("Error reading current directory: {}", err))
+        })?;
+        validate_artifact_path(&cwd, path).map_err(|err| {
+            DeployError::Config(format!(
+                "{}\nUse `--allow-external` to include it anyway",
+                err
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Lists every file about to be zipped with the total size, asking whether
+/// to continue when running in a terminal unless `force` is set.
+fn confirm_entries(app: &App, force: bool, allow_external: bool) -> Result<()> {
+    let entries = collect_entries(
+        app.artifacts(),
+        app.max_artifact_size(),
+        app.max_depth(),
+        allow_external,
+    )?;
+    let files: Vec<_> = entries.iter().filter(|entry| !entry.is_dir).collect();
+    emit(DeployEvent::EntriesListed { files: &files });
+
+    let interactive = std::io::stdout().is_terminal() && std::io::stdin().is_terminal();
+    if !interactive {
+        return Ok(());
+    }
+    if force {
+        warn!("--force: continuing without confirmation");
+        return Ok(());
+    }
+    let proceed = Confirm::new()
+        .with_prompt("Continue?")
+        .default(true)
+        .interact()
+        .map_err(|err| DeployError::Other(format!("Error reading confirmation: {}", err)))?;
+    if !proceed {
+        return Err(DeployError::Other("Deploy aborted".to_string()));
+    }
+    Ok(())
+}
+
+#[instrument(name = "zip", skip_all)]
+fn zip_artifacts(app: &App, cli: &Cli) -> Result<()> {
+    let options = ArchiveOptions {
+        compression: app.compression(),
+        compression_level: app.compression_level(),
+        store_extensions: app.store_extensions(),
+        max_artifact_size: app.max_artifact_size(),
+        max_archive_size: app.max_archive_size(),
+        max_depth: app.max_depth(),
+        allow_external: cli.allow_external,
+        reproducible: cli.reproducible,
+    };
+    let out = Path::new(app.archive_path());
+    let summary = match app.source() {
+        Source::Artifacts => build_archive(app.artifacts(), out, &options)?,
+        Source::Git => git_archive(app.git_ref(), out, &options)?,
+    };
+    debug!(bytes = summary.size, "archive written");
+    if summary.manifest.len() == 0 {
+        return Err(DeployError::Build(format!(
+            "Archive `{}` has no files, the artifacts are empty",
+            app.archive_name()
+        )));
+    }
+
+    let manifest_path = Manifest::path_for(app.archive_path());
+    summary.manifest.write(&manifest_path)?;
+    debug!(
+        path = %manifest_path,
+        files = summary.manifest.len(),
+        bytes = summary.manifest.total_size(),
+        "manifest written"
+    );
+
+    Ok(())
+}
+
+/// Uploads the archive, or the `--raw` file, at `local` into `host_path`.
+/// It's written to a `.partial` file first and renamed once complete, so an
+/// interrupted upload never leaves a truncated file under the final name.
+#[instrument(skip_all)]
+async fn upload(
+    session: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    cli: &Cli,
+    app: &App,
+    host: &Host,
+    local: &Path,
+    host_output_path: &str,
+) -> Result<()> {
+    let platform = host.platform();
+    let limit_rate = cli.limit_rate;
+    let chunk_size = host.sftp().max_write_len().unwrap_or(upload::CHUNK_SIZE);
+
+    let mut mkdir = match platform {
+        Platform::Unix => {
+            let mut command = session.command("mkdir");
+            command.args(["-p", app.host_path()]);
+            command
         }
+        Platform::Windows => session.raw_command(powershell(&format!(
+            "New-Item -ItemType Directory -Force -Path {} | Out-Null",
+            ps_quote(app.host_path())
+        ))),
+    };
+    unwrap_command_stderr(&mut mkdir)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `mkdir` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!("Error creating app host directory:\n{}", err))
+        })?;
+    if let Some(mode) = app.host_path_mode()? {
+        set_host_path_mode(fs, app, platform, mode).await?;
+    }
+
+    let partial = partial_path(host_output_path);
+    debug!(path = %partial, limit_rate, "writing archive");
+
+    let started = Instant::now();
+    let written = fs
+        .upload(local, &partial, limit_rate.map(|kb| kb * 1024), chunk_size)
+        .await
+        .map_err(|err| {
+            DeployError::Remote(format!("Error writing build file into host's fs: {}", err))
+        })?;
+    debug!(bytes = written, "archive written");
+    emit(DeployEvent::Transferred {
+        bytes: written,
+        elapsed: started.elapsed(),
+    });
 
-        fs.set_permissions(
-            &host_entrypoint_path,
-            Permissions::new()
-                .set_execute_by_group(true)
-                .set_execute_by_owner(true)
-                .clone(),
+    fs.rename(&partial, host_output_path).await.map_err(|err| {
+        DeployError::Remote(format!("Error moving `{}` into place: {}", partial, err))
+    })?;
+
+    if cli.raw {
+        emit(DeployEvent::RawUploaded {
+            path: host_output_path,
+        });
+        return Ok(());
+    }
+
+    if app.upload_manifest() {
+        let manifest = Manifest::path_for(app.archive_path());
+        let remote = Manifest::path_for(host_output_path);
+        fs.upload(
+            Path::new(&manifest),
+            &remote,
+            limit_rate.map(|kb| kb * 1024),
+            chunk_size,
         )
         .await
-        .expect("Error giving entrypoint file execute permissions");
-    } else {
-        session
-            .command("cd")
-            .raw_args(&[app.host_path(), "&&"])
-            .args(&["COMPOSE_STATUS_STDOUT=1", "docker-compose", "build"])
-            .output()
+        .map_err(|err| {
+            DeployError::Remote(format!("Error writing manifest into host's fs: {}", err))
+        })?;
+        debug!(path = %remote, "manifest written");
+    }
+
+    emit(DeployEvent::Uploaded);
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn extract(
+    session: &Remote<'_>,
+    app: &App,
+    platform: Platform,
+    host_output_path: &str,
+) -> Result<()> {
+    let mut unzip = match platform {
+        Platform::Unix => {
+            let mut command = session.command("unzip");
+            command
+                .args([app.overwrite().unzip_flag(), host_output_path])
+                .args(["-d", app.host_path()]);
+            command
+        }
+        Platform::Windows => {
+            if !matches!(app.overwrite(), Overwrite::Always) {
+                return Err(DeployError::Config(
+                    "Only the `always` overwrite policy is supported on Windows hosts".to_string(),
+                ));
+            }
+            session.raw_command(powershell(&format!(
+                "Expand-Archive -Force -LiteralPath {} -DestinationPath {}",
+                ps_quote(host_output_path),
+                ps_quote(app.host_path())
+            )))
+        }
+    };
+    unwrap_command_stderr(&mut unzip)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `unzip` on host: {}", err)))?
+        .check()
+        .map_err(|err| DeployError::Remote(format!("Error unzipping output file:\n{}", err)))?;
+
+    Ok(())
+}
+
+/// Removes the files of the previous deploy that are no longer in the
+/// archive from `host_path`, in batches to keep each command line short.
+#[instrument(skip_all)]
+async fn remove_entries(
+    session: &Remote<'_>,
+    app: &App,
+    platform: Platform,
+    paths: &[String],
+) -> Result<()> {
+    for batch in paths.chunks(100) {
+        debug!(files = batch.len(), "removing deleted entries");
+        let mut remove = match platform {
+            Platform::Unix => {
+                let mut command = session.command("cd");
+                command
+                    .raw_args([app.host_path(), "&&"])
+                    .args(["rm", "-f", "--"])
+                    .args(batch);
+                command
+            }
+            Platform::Windows => {
+                let paths = batch
+                    .iter()
+                    .map(|path| ps_quote(&platform.join(app.host_path(), path)))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                session.raw_command(powershell(&format!(
+                    "Remove-Item -Force -ErrorAction SilentlyContinue -LiteralPath {}",
+                    paths
+                )))
+            }
+        };
+        unwrap_command_stderr(&mut remove)
+            .await
+            .map_err(|err| DeployError::Remote(format!("Error running `rm` on host: {}", err)))?
+            .check()
+            .map_err(|err| {
+                DeployError::Remote(format!("Error removing deleted entries:\n{}", err))
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Runs the `after_extract` hooks in `host_path`, aborting on the first one
+/// that exits with a non-zero status.
+#[instrument(skip_all)]
+async fn after_extract(
+    session: &Remote<'_>,
+    app: &App,
+    env: &BTreeMap<String, String>,
+) -> Result<()> {
+    let user = app.run_as().after_extract();
+    for hook in app.after_extract() {
+        emit(DeployEvent::Hook { command: hook });
+
+        let mut command = session.command("cd");
+        command.raw_args([app.host_path(), "&&"]);
+        if user.is_none() && env.is_empty() {
+            command.raw_arg(hook);
+        } else {
+            with_env(as_user(&mut command, user), env).args(["sh", "-c", hook]);
+        }
+        stream_command(&mut command)
+            .await
+            .map_err(|err| {
+                DeployError::Remote(format!("Error running `{}` on host: {}", hook, err))
+            })?
+            .check_status()
+            .map_err(|err| DeployError::Remote(format!("Hook `{}` failed:\n{}", hook, err)))?;
+    }
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn restart(
+    session: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    app: &App,
+    host: &Host,
+    force: bool,
+) -> Result<()> {
+    for strategy in app.strategies() {
+        match strategy {
+            Strategy::Entrypoint => restart_entrypoint(session, fs, app, host).await?,
+            Strategy::Compose => restart_compose(session, app, host.env(), force).await?,
+            Strategy::Systemd => restart_systemd(session, app).await?,
+            Strategy::Static => reload_static(session, app, host.env()).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Uploads each entrypoint that isn't part of the artifacts and makes it
+/// executable. With `run_entrypoint` they're then run in order from
+/// `host_path`, stopping at the first one that fails.
+async fn restart_entrypoint(
+    session: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    app: &App,
+    host: &Host,
+) -> Result<()> {
+    let platform = host.platform();
+    for entrypoint in app.entrypoints() {
+        prepare_entrypoint(fs, app, platform, entrypoint).await?;
+    }
+
+    if !app.run_entrypoint() {
+        return Ok(());
+    }
+
+    for entrypoint in app.entrypoints() {
+        emit(DeployEvent::Entrypoint { entrypoint });
+        let mut command = session.command("cd");
+        command.raw_args([app.host_path(), "&&"]);
+        match platform {
+            Platform::Unix => with_env(as_user(&mut command, app.run_as().restart()), host.env())
+                .arg(platform.join(".", entrypoint)),
+            // `cmd.exe` doesn't understand the POSIX quoting `arg` applies.
+            Platform::Windows => command.raw_arg(platform.join(".", entrypoint)),
+        };
+        stream_command(&mut command)
             .await
-            .expect("Error running `docker-compose build` command");
+            .map_err(|err| {
+                DeployError::Remote(format!(
+                    "Error running entrypoint `{}`: {}",
+                    entrypoint, err
+                ))
+            })?
+            .check_status()
+            .map_err(|err| {
+                DeployError::Remote(format!("Entrypoint `{}` failed:\n{}", entrypoint, err))
+            })?;
+    }
 
-        session
-            .command("cd")
-            .raw_args(&[app.host_path(), "&&"])
-            .args(&["docker-compose", "up", "-d"])
-            .output()
+    Ok(())
+}
+
+/// Uploads `entrypoint` when no artifact provides it, then makes it
+/// executable. Windows has no execute bit, so permissions are left alone
+/// there.
+async fn prepare_entrypoint(
+    fs: &RemoteFs<'_>,
+    app: &App,
+    platform: Platform,
+    entrypoint: &str,
+) -> Result<()> {
+    emit(DeployEvent::EntrypointFound { entrypoint });
+
+    let host_entrypoint_path = platform.join(app.host_path(), entrypoint);
+    if let Some(local) = entrypoint_upload(app, entrypoint)? {
+        emit(DeployEvent::EntrypointUploading { entrypoint });
+        fs.upload(local, &host_entrypoint_path, None, upload::CHUNK_SIZE)
             .await
-            .expect("Error running `docker-compose up -d` command");
+            .map_err(|err| {
+                DeployError::Remote(format!(
+                    "Error writing entrypoint file into host's fs: {}",
+                    err
+                ))
+            })?;
+    }
+
+    if platform == Platform::Windows {
+        return Ok(());
+    }
+
+    fs.make_executable(&host_entrypoint_path)
+        .await
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error giving entrypoint file execute permissions: {}",
+                err
+            ))
+        })
+}
+
+/// The local file to upload for `entrypoint`, `None` when an artifact
+/// already lands it on the host. Errors when it doesn't exist locally.
+fn entrypoint_upload<'e>(app: &App, entrypoint: &'e str) -> Result<Option<&'e Path>> {
+    if app.artifacts().iter().any(|a| a.lands_at(entrypoint)) {
+        return Ok(None);
+    }
+    if let Err(err) = std::fs::metadata(entrypoint) {
+        return Err(DeployError::Config(format!(
+            "Error reading entrypoint file `{}`: {}",
+            entrypoint, err
+        )));
+    }
+    Ok(Some(Path::new(entrypoint)))
+}
+
+async fn restart_compose(
+    session: &Remote<'_>,
+    app: &App,
+    env: &BTreeMap<String, String>,
+    force: bool,
+) -> Result<()> {
+    let user = app.run_as().restart();
+
+    if app.compose_build_local() {
+        // The tarball was extracted with the artifacts, see
+        // `build_compose_images`.
+        let images = app.compose_images_path();
+        let images = Path::new(&images)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or(images);
+        stream_command(
+            with_env(
+                as_user(
+                    session.command("cd").raw_args([app.host_path(), "&&"]),
+                    user,
+                ),
+                env,
+            )
+            .args(["docker", "load", "-i", &images]),
+        )
+        .await
+        .map_err(|err| {
+            DeployError::Remote(format!("Error running `docker load` command: {}", err))
+        })?
+        .check_status()
+        .map_err(|err| DeployError::Remote(format!("Error loading the images:\n{}", err)))?;
+    } else {
+        if let Some(min) = app.min_build_memory_mb() {
+            check_build_memory(session, min, force).await?;
+        }
+
+        // Progress goes to stdout, so it isn't mistaken for errors.
+        let mut build_env = env.clone();
+        build_env
+            .entry("COMPOSE_STATUS_STDOUT".to_string())
+            .or_insert_with(|| "1".to_string());
+        stream_command(
+            with_env(
+                as_user(
+                    session.command("cd").raw_args([app.host_path(), "&&"]),
+                    user,
+                ),
+                &build_env,
+            )
+            .args(["docker-compose", "build"]),
+        )
+        .await
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error running `docker-compose build` command: {}",
+                err
+            ))
+        })?
+        .check_status()
+        .map_err(|err| DeployError::Remote(format!("Error building the stack:\n{}", err)))?;
+    }
+
+    stream_command(
+        with_env(
+            as_user(
+                session.command("cd").raw_args([app.host_path(), "&&"]),
+                user,
+            ),
+            env,
+        )
+        .args(["docker-compose", "up", "-d"]),
+    )
+    .await
+    .map_err(|err| {
+        DeployError::Remote(format!(
+            "Error running `docker-compose up -d` command: {}",
+            err
+        ))
+    })?
+    .check_status()
+    .map_err(|err| DeployError::Remote(format!("Error starting the stack:\n{}", err)))?;
+
+    emit(DeployEvent::StackBuilt);
+
+    Ok(())
+}
+
+/// Aborts the compose build when the host has less than `min` MiB of memory
+/// available, as reported by `free -m`, unless `force` is set.
+async fn check_build_memory(session: &Remote<'_>, min: u64, force: bool) -> Result<()> {
+    let out = unwrap_command_stderr(session.command("free").arg("-m"))
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `free` on host: {}", err)))?
+        .check()
+        .map_err(|err| DeployError::Remote(format!("Error reading host memory:\n{}", err)))?;
+    let available = available_memory(&out.stdout).ok_or_else(|| {
+        DeployError::Remote(format!(
+            "Couldn't read available memory from `free -m`:\n{}",
+            out.stdout.trim_end()
+        ))
+    })?;
+    debug!(available, min, "host memory before build");
+
+    if available >= min {
+        return Ok(());
+    }
+    if force {
+        warn!(
+            "--force: building with {} MiB available, under `min_build_memory_mb` of {}",
+            available, min
+        );
+        return Ok(());
+    }
+    Err(DeployError::Remote(format!(
+        "Host has {} MiB of memory available, under `min_build_memory_mb` of {}. Build the images elsewhere and ship them, or use `--force` to build anyway",
+        available, min
+    )))
+}
+
+/// Available memory in MiB from `free -m` output, using the `free` column
+/// when the older format has no `available` one.
+fn available_memory(free: &str) -> Option<u64> {
+    let mut lines = free.lines();
+    let header: Vec<&str> = lines.next()?.split_whitespace().collect();
+    let column = header
+        .iter()
+        .position(|name| *name == "available")
+        .or_else(|| header.iter().position(|name| *name == "free"))?;
+    // The `Mem:` label has no header above it.
+    let row: Vec<&str> = lines
+        .find(|line| line.starts_with("Mem:"))?
+        .split_whitespace()
+        .skip(1)
+        .collect();
+    row.get(column)?.parse().ok()
+}
+
+async fn restart_systemd(session: &Remote<'_>, app: &App) -> Result<()> {
+    let service = app
+        .service_name()
+        .as_ref()
+        .expect("systemd strategy without a service_name");
+
+    // systemctl already runs through sudo, as root unless `run_as` says
+    // otherwise.
+    let mut command = session.command("sudo");
+    if let Some(user) = app.run_as().restart() {
+        command.args(["-n", "-u", user]);
+    }
+    unwrap_command_stderr(command.args(["systemctl", "restart", service]))
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `systemctl` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!("Error restarting service `{}`:\n{}", service, err))
+        })?;
 
-        println!("Stack built successfully!");
+    emit(DeployEvent::ServiceRestarted { service });
+
+    Ok(())
+}
+
+async fn reload_static(
+    session: &Remote<'_>,
+    app: &App,
+    env: &BTreeMap<String, String>,
+) -> Result<()> {
+    let reload = app.reload_command();
+    let user = app.run_as().restart();
+
+    let mut command = session.command("cd");
+    command.raw_args([app.host_path(), "&&"]);
+    if user.is_none() && env.is_empty() {
+        command.raw_arg(reload);
+    } else {
+        with_env(as_user(&mut command, user), env).args(["sh", "-c", reload]);
     }
+    stream_command(&mut command)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `{}` on host: {}", reload, err)))?
+        .check_status()
+        .map_err(|err| DeployError::Remote(format!("Error reloading the site:\n{}", err)))?;
 
-    drop(fs);
+    emit(DeployEvent::SiteReloaded);
 
-    let (_, _) = futures::join!(session.close(), sftp.close());
+    Ok(())
+}
 
-    println!("Connection closed!")
+/// Ensures an artifact path stays inside `base`, rejecting absolute paths,
+/// `..` traversal and symlinks that resolve outside of it.
+fn validate_artifact_path(base: &Path, artifact: &str) -> Result<(), String> {
+    let path = Path::new(artifact);
+    if path.is_absolute() {
+        return Err(format!("Artifact `{}` is an absolute path", artifact));
+    }
+    if path.components().any(|c| c == Component::ParentDir) {
+        return Err(format!(
+            "Artifact `{}` points outside the project directory",
+            artifact
+        ));
+    }
+    if let (Ok(resolved), Ok(base)) = (base.join(path).canonicalize(), base.canonicalize()) {
+        if !resolved.starts_with(&base) {
+            return Err(format!(
+                "Artifact `{}` resolves to `{}`, outside the project directory",
+                artifact,
+                resolved.display()
+            ));
+        }
+    }
+    Ok(())
 }
 
-async fn unwrap_command_stderr(command: &mut OwningCommand<&'_ Session>) -> Result<String, String> {
-    match command.output().await {
-        Ok(out) => {
-            if out.stderr.len() > 0 {
-                return Err(
-                    format!("{}", String::from_utf8(out.stderr.to_vec()).unwrap()).to_string(),
-                );
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use serde_json::json;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    /// Entrypoint paths are relative to the current directory, which the
+    /// tests changing it take turns with.
+    static CWD: Mutex<()> = Mutex::const_new(());
+
+    /// A temporary directory made the current one until dropped, when the
+    /// previous one is restored before it's removed.
+    struct InTempDir {
+        previous: PathBuf,
+        _dir: tempfile::TempDir,
+        _turn: MutexGuard<'static, ()>,
+    }
+
+    impl InTempDir {
+        async fn enter() -> InTempDir {
+            let turn = CWD.lock().await;
+            let previous = current_dir().unwrap();
+            let dir = tempfile::tempdir().unwrap();
+            set_current_dir(dir.path()).unwrap();
+            InTempDir {
+                previous,
+                _dir: dir,
+                _turn: turn,
             }
-            Ok(String::from_utf8(out.stdout).unwrap())
         }
-        Err(err) => {
-            panic!("Error running command:\n{}", err);
+    }
+
+    impl Drop for InTempDir {
+        fn drop(&mut self) {
+            let _ = set_current_dir(&self.previous);
         }
     }
+
+    /// App deploying `artifacts` with the entrypoint at `entrypoint`.
+    fn entrypoint_app(entrypoint: &Path, artifacts: serde_json::Value) -> App {
+        serde_json::from_value(json!({
+            "name": "app",
+            "host_path": "/srv/app",
+            "build_output_file": "app.zip",
+            "artifacts": artifacts,
+            "entrypoint": entrypoint.display().to_string(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn entrypoint_in_artifacts_is_not_uploaded() {
+        // Built elsewhere and renamed, so there's no local `run.sh` at all.
+        let app = entrypoint_app(
+            Path::new("run.sh"),
+            json!([{ "src": "build/run.sh", "dest": "run.sh" }]),
+        );
+        assert_eq!(entrypoint_upload(&app, "run.sh").unwrap(), None);
+    }
+
+    #[test]
+    fn entrypoint_outside_artifacts_is_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let entrypoint = dir.path().join("run.sh");
+        std::fs::write(&entrypoint, "#!/bin/sh\n").unwrap();
+        let entrypoint = entrypoint.display().to_string();
+
+        let app = entrypoint_app(Path::new(&entrypoint), json!(["dist"]));
+        assert_eq!(
+            entrypoint_upload(&app, &entrypoint).unwrap(),
+            Some(Path::new(&entrypoint))
+        );
+    }
+
+    #[test]
+    fn missing_entrypoint_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let entrypoint = dir.path().join("run.sh").display().to_string();
+
+        let app = entrypoint_app(Path::new(&entrypoint), json!(["dist"]));
+        let err = entrypoint_upload(&app, &entrypoint).unwrap_err();
+        assert!(matches!(err, DeployError::Config(_)));
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Error reading entrypoint file `{}`", entrypoint)));
+    }
+
+    /// App deploying `artifacts` to the local `host` with the `run.sh`
+    /// entrypoint.
+    fn local_entrypoint_app(host: &Path, artifacts: serde_json::Value) -> App {
+        serde_json::from_value(json!({
+            "name": "app",
+            "host_path": host.display().to_string(),
+            "archive_name": "app.zip",
+            "artifacts": artifacts,
+            "entrypoint": "run.sh",
+        }))
+        .unwrap()
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode()
+    }
+
+    #[tokio::test]
+    async fn extracted_entrypoint_is_made_executable_on_a_local_host() {
+        let _local = InTempDir::enter().await;
+        let host = tempfile::tempdir().unwrap();
+        fs::write("run.sh", "local").unwrap();
+        // As extracted from the archive.
+        let extracted = host.path().join("run.sh");
+        fs::write(&extracted, "extracted").unwrap();
+        fs::set_permissions(&extracted, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let app = local_entrypoint_app(host.path(), json!(["run.sh"]));
+        prepare_entrypoint(&RemoteFs::Local, &app, Platform::Unix, "run.sh")
+            .await
+            .unwrap();
+
+        assert_eq!(fs::read_to_string(&extracted).unwrap(), "extracted");
+        assert_eq!(mode(&extracted) & 0o777, 0o754);
+    }
+
+    #[tokio::test]
+    async fn entrypoint_is_uploaded_executable_to_a_local_host() {
+        let _local = InTempDir::enter().await;
+        let host = tempfile::tempdir().unwrap();
+        fs::write("run.sh", "#!/bin/sh\n").unwrap();
+        fs::set_permissions("run.sh", fs::Permissions::from_mode(0o600)).unwrap();
+
+        let app = local_entrypoint_app(host.path(), json!(["dist"]));
+        prepare_entrypoint(&RemoteFs::Local, &app, Platform::Unix, "run.sh")
+            .await
+            .unwrap();
+
+        let uploaded = host.path().join("run.sh");
+        assert_eq!(fs::read_to_string(&uploaded).unwrap(), "#!/bin/sh\n");
+        // Owner and group execute, others left alone.
+        assert_eq!(mode(&uploaded) & 0o777, 0o710);
+    }
+
+    #[tokio::test]
+    async fn tilde_host_path_is_expanded_on_a_local_host() {
+        let app: App = serde_json::from_value(json!({
+            "name": "app",
+            "host_path": "~/app",
+            "archive_name": "app.zip",
+            "artifacts": ["dist"],
+        }))
+        .unwrap();
+
+        let expanded = with_home_expanded(&Remote::Local, &app, Platform::Unix)
+            .await
+            .unwrap()
+            .unwrap();
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            expanded.host_path(),
+            &format!("{}/app", home.trim_end_matches('/'))
+        );
+    }
 }
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use tracing::debug;
+
+use crate::error::{DeployError, Result};
+
+/// Every file that went into the archive, written next to it as
+/// `<archive_name>.manifest.json` so what shipped can be audited and
+/// diffed between releases.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// The hash `hasher` finished with, in lowercase hex as manifests store it.
+pub fn hex_digest(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl Manifest {
+    /// Local manifest path for the archive at `archive_name`.
+    pub fn path_for(archive_name: &str) -> String {
+        format!("{}.manifest.json", archive_name)
+    }
+
+    /// Local copy of the manifest last deployed to the host named `host`,
+    /// which `--only-upload-changed-entries` diffs the next archive against.
+    pub fn deployed_path(archive_name: &str, host: &str) -> String {
+        let host: String = host
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}.{}.deployed.json", archive_name, host)
+    }
+
+    /// Manifest written at `path`, `None` when there's none or it can't be
+    /// read, so the next deploy uploads the full archive.
+    pub fn load(path: &str) -> Option<Self> {
+        let content = fs::read(path).ok()?;
+        match serde_json::from_slice(&content) {
+            Ok(manifest) => Some(manifest),
+            Err(err) => {
+                debug!(path, %err, "ignoring unreadable manifest");
+                None
+            }
+        }
+    }
+
+    /// Paths that are new since `previous` or whose content changed.
+    pub fn changed_since(&self, previous: &Manifest) -> Vec<&str> {
+        let previous = previous.hashes();
+        self.files
+            .iter()
+            .filter(|entry| previous.get(entry.path.as_str()) != Some(&entry.sha256.as_str()))
+            .map(|entry| entry.path.as_str())
+            .collect()
+    }
+
+    /// Paths of `previous` that are gone from this manifest.
+    pub fn removed_since<'a>(&self, previous: &'a Manifest) -> Vec<&'a str> {
+        let current = self.hashes();
+        previous
+            .files
+            .iter()
+            .filter(|entry| !current.contains_key(entry.path.as_str()))
+            .map(|entry| entry.path.as_str())
+            .collect()
+    }
+
+    /// SHA-256 of each file, by path.
+    pub fn hashes(&self) -> HashMap<&str, &str> {
+        self.files
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.sha256.as_str()))
+            .collect()
+    }
+
+    /// Adds a file of `size` bytes whose content went through `hasher`.
+    pub fn add(&mut self, path: &str, size: u64, hasher: Sha256) {
+        self.files.push(ManifestEntry {
+            path: path.to_string(),
+            size,
+            sha256: hex_digest(hasher),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Total size in bytes of the files, before compression.
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|entry| entry.size).sum()
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        let content = serde_json::to_vec_pretty(self)
+            .map_err(|err| DeployError::Build(format!("Error serializing manifest: {}", err)))?;
+        fs::write(path, content)
+            .map_err(|err| DeployError::Build(format!("Error writing `{}`: {}", path, err)))
+    }
+}
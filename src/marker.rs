@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sha2::Digest;
+use tracing::{debug, warn};
+
+use crate::archive::copy_hashed;
+use crate::config::{App, Platform};
+use crate::error::Result;
+use crate::transfer::RemoteFs;
+
+/// Outcome of the last deploy, written into `host_path` as the app's
+/// `deploy_marker` or `deploy_failed_marker` so a dashboard or cron job can
+/// check on deploys without parsing logs.
+#[derive(Debug, Serialize)]
+struct DeployMarker {
+    status: &'static str,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    /// SHA-256 of the uploaded archive, or of the file with `--raw`.
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Records `result` on the host. A successful deploy also clears the failure
+/// marker of an earlier one. Failing to write is only warned about, as the
+/// deploy itself is done by now.
+pub async fn write(
+    fs: &RemoteFs<'_>,
+    app: &App,
+    platform: Platform,
+    local: &Path,
+    result: &Result<()>,
+) {
+    let marker = DeployMarker {
+        status: if result.is_ok() { "success" } else { "failed" },
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+        version: version(local),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
+    let name = match result {
+        Ok(_) => app.deploy_marker(),
+        Err(_) => app.deploy_failed_marker(),
+    };
+    let path = platform.join(app.host_path(), name);
+
+    let content = match serde_json::to_vec_pretty(&marker) {
+        Ok(content) => content,
+        Err(err) => {
+            warn!(%err, "error serializing deploy marker");
+            return;
+        }
+    };
+    if let Err(err) = fs.write(&path, &content).await {
+        warn!(path, %err, "error writing deploy marker");
+        return;
+    }
+
+    if result.is_ok() {
+        let failed = platform.join(app.host_path(), app.deploy_failed_marker());
+        if let Err(err) = fs.remove(&failed).await {
+            debug!(path = %failed, %err, "no failed deploy marker removed");
+        }
+    }
+}
+
+fn version(local: &Path) -> Option<String> {
+    let (_, hasher) = File::open(local)
+        .and_then(|mut file| copy_hashed(&mut file, &mut io::sink()))
+        .map_err(|err| debug!(path = %local.display(), %err, "no version for deploy marker"))
+        .ok()?;
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+    )
+}
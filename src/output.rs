@@ -0,0 +1,155 @@
+use std::fmt::Display;
+
+use console::style;
+
+use crate::events::DeployEvent;
+
+/// Colors are used only on a terminal and when `NO_COLOR` is unset;
+/// `--no-color` turns them off regardless.
+pub fn init(no_color: bool) {
+    if no_color {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+    }
+}
+
+pub fn success(message: impl Display) {
+    println!("{}", style(message).green());
+}
+
+pub fn failure(message: impl Display) {
+    eprintln!("{}", style(message).for_stderr().red());
+}
+
+pub fn skipped(message: impl Display) {
+    println!("{}", style(message).yellow());
+}
+
+/// The CLI's rendering of deploy progress, registered with
+/// [`crate::events::on_event`] on startup.
+pub fn event(event: &DeployEvent) {
+    match event {
+        DeployEvent::HostsSelected { hosts } => {
+            eprintln!("Selected {} host(s): {}", hosts.len(), hosts.join(", "))
+        }
+        DeployEvent::Started { app } => println!("Deploying app: {}", app),
+        DeployEvent::Unchanged => skipped("No changes in `when_changed` paths, skipping deploy"),
+        DeployEvent::EntriesListed { files } => {
+            println!("Files to archive:");
+            for entry in *files {
+                println!("  {} ({})", entry.name, human_size(entry.size));
+            }
+            let total: u64 = files.iter().map(|entry| entry.size).sum();
+            println!("{} file(s), {} in total", files.len(), human_size(total));
+        }
+        DeployEvent::BuildSkipped => println!("No build command, skipping build"),
+        DeployEvent::Built => success("Build ran successfully!"),
+        DeployEvent::ConnectRetry {
+            error,
+            attempt,
+            retries,
+        } => failure(format!(
+            "Error connecting to host: {}\nRetrying ({}/{})...",
+            error, attempt, retries
+        )),
+        DeployEvent::DeployRetry {
+            error,
+            attempt,
+            retries,
+        } => failure(format!(
+            "{}\nRetrying deploy ({}/{})...",
+            error, attempt, retries
+        )),
+        DeployEvent::BuildRetry {
+            error,
+            attempt,
+            retries,
+        } => failure(format!(
+            "{}\nRetrying build ({}/{})...",
+            error, attempt, retries
+        )),
+        DeployEvent::DeltaPrepared { changed, removed } => println!(
+            "Uploading {} changed entries, removing {}...",
+            changed, removed
+        ),
+        DeployEvent::Transferred { bytes, elapsed } => {
+            let rate = *bytes as f64 / elapsed.as_secs_f64().max(0.001);
+            println!(
+                "Transferred {} in {:.1?} ({}/s)",
+                human_size(*bytes),
+                elapsed,
+                human_size(rate as u64)
+            )
+        }
+        DeployEvent::Uploaded => println!("Build output file written! Unzipping..."),
+        DeployEvent::RawUploaded { path } => println!("Uploaded `{}`", path),
+        DeployEvent::RemoteOutput { line } => println!("[remote] {}", line),
+        DeployEvent::Hook { command } => println!("Running `{}`...", command),
+        DeployEvent::EntrypointFound { entrypoint } => {
+            println!("Found entrypoint file `{}`", entrypoint)
+        }
+        DeployEvent::EntrypointUploading { entrypoint } => {
+            println!(
+                "Entrypoint `{}` not found on artifacts, uploading...",
+                entrypoint
+            )
+        }
+        DeployEvent::Entrypoint { entrypoint } => {
+            println!("Running entrypoint `{}`...", entrypoint)
+        }
+        DeployEvent::Downloaded { url, bytes } => {
+            success(format!("Downloaded `{}` ({})", url, human_size(*bytes)))
+        }
+        DeployEvent::ImagesSaved { path } => success(format!("Images saved to `{}`!", path)),
+        DeployEvent::StackBuilt => success("Stack built successfully!"),
+        DeployEvent::ServiceRestarted { service } => {
+            success(format!("Service `{}` restarted!", service))
+        }
+        DeployEvent::SiteReloaded => success("Site reloaded!"),
+        DeployEvent::WarmedUp { url, requests } => {
+            println!("Sent {} warm-up request(s) to `{}`", requests, url)
+        }
+        DeployEvent::SmokeTest { command } => println!("Running smoke test `{}`...", command),
+        DeployEvent::SmokeTestPassed => success("Smoke test passed!"),
+        DeployEvent::ArchiveSigned { path } => success(format!("Archive signed to `{}`!", path)),
+        DeployEvent::SignatureVerified => success("Archive signature verified on the host!"),
+        DeployEvent::FilesVerified { count } => {
+            success(format!("All {} archive file(s) are on the host!", count))
+        }
+        DeployEvent::RolledBack { path } => {
+            skipped(format!("Rolled `{}` back to before the deploy", path))
+        }
+        DeployEvent::ConnectionClosed => println!("Connection closed!"),
+        DeployEvent::HostDeployed { app, host, elapsed } => success(format!(
+            "\u{2713} deployed {} to {} in {:.1?}",
+            app, host, elapsed
+        )),
+        DeployEvent::HostSkipped { host } => skipped(format!("- skipped {}", host)),
+        DeployEvent::StepRan { step, host } => success(format!("Ran `{}` on `{}`", step, host)),
+        DeployEvent::AppFailed { error } => failure(error),
+        DeployEvent::AppsFinished { apps } => {
+            println!("Apps:");
+            for (path, name) in *apps {
+                match name {
+                    Some(name) => success(format!("  \u{2713} {} ({})", name, path.display())),
+                    None => failure(format!("  \u{2717} {}", path.display())),
+                }
+            }
+        }
+    }
+}
+
+/// Formats `bytes` with a binary unit, e.g. `1.5 MiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::env::set_current_dir;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::archive::{collect_entries, copy_hashed};
+use crate::cli::Cli;
+use crate::config::{App, Host};
+use crate::error::{DeployError, Result};
+use crate::manifest::hex_digest;
+use crate::{check_app, deploy};
+
+/// Bumped whenever the plan format changes incompatibly.
+const PLAN_VERSION: u32 = 2;
+
+/// Everything a deploy resolved to: the app and host config with defaults
+/// applied, the directory paths are relative to, and the files the archive
+/// would hold when the plan was made. `apply` runs exactly this, without
+/// reading any config file again.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Plan {
+    version: u32,
+    dir: PathBuf,
+    app: App,
+    hosts: Vec<Host>,
+    files: Vec<PlannedFile>,
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+struct PlannedFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Writes the plan for `app` and `hosts` to `output`.
+pub fn plan(cli: &Cli, app: App, hosts: Vec<Host>, dir: PathBuf, output: &Path) -> Result<()> {
+    let files = planned_files(&app, cli.allow_external)?;
+    let plan = Plan {
+        version: PLAN_VERSION,
+        dir,
+        app,
+        hosts,
+        files,
+    };
+
+    let mut content = serde_json::to_string_pretty(&plan)
+        .map_err(|err| DeployError::Other(format!("Error serializing plan: {}", err)))?;
+    content.push('\n');
+    fs::write(output, content).map_err(|err| {
+        DeployError::Other(format!("Error writing `{}`: {}", output.display(), err))
+    })?;
+
+    println!(
+        "Plan written to `{}`: {} to {} host(s), {} file(s)",
+        output.display(),
+        plan.app.name(),
+        plan.hosts.len(),
+        plan.files.len()
+    );
+    Ok(())
+}
+
+/// Deploys the plan at `path`. Fails before deploying when the plan was
+/// made by an incompatible version, when the app no longer passes the checks
+/// a deploy runs, or when the artifacts' files differ from the plan's.
+pub async fn apply(cli: &Cli, path: &Path) -> Result<()> {
+    let content = fs::read(path).map_err(|err| {
+        DeployError::Config(format!("Error opening `{}`: {}", path.display(), err))
+    })?;
+    let mut plan: Plan = serde_json::from_slice(&content).map_err(|err| {
+        DeployError::Config(format!("Error parsing `{}`: {}", path.display(), err))
+    })?;
+    if plan.version != PLAN_VERSION {
+        return Err(DeployError::Config(format!(
+            "Plan `{}` has version {}, expected {}",
+            path.display(),
+            plan.version,
+            PLAN_VERSION
+        )));
+    }
+    plan.app.resolve_archive_name(path)?;
+
+    set_current_dir(&plan.dir).map_err(|err| {
+        DeployError::Config(format!(
+            "Error changing directory to `{}`: {}",
+            plan.dir.display(),
+            err
+        ))
+    })?;
+    check_app(cli, &mut plan.app, &plan.dir)?;
+    check_files(&plan, cli.allow_external)?;
+
+    deploy(cli, &plan.app, &plan.hosts).await
+}
+
+/// Errors when the artifacts' files, or their content, changed since the
+/// plan was made, listing each difference.
+fn check_files(plan: &Plan, allow_external: bool) -> Result<()> {
+    let current = planned_files(&plan.app, allow_external)?;
+    if current == plan.files {
+        return Ok(());
+    }
+
+    let by_path = |files: &[PlannedFile]| -> HashMap<String, (u64, String)> {
+        files
+            .iter()
+            .map(|file| (file.path.clone(), (file.size, file.sha256.clone())))
+            .collect()
+    };
+    let (planned, current) = (by_path(&plan.files), by_path(&current));
+    let mut differences: Vec<String> = planned
+        .iter()
+        .filter_map(|(path, (size, sha256))| match current.get(path) {
+            None => Some(format!("  gone: {}", path)),
+            Some((now, _)) if now != size => {
+                Some(format!("  changed: {} ({} -> {} bytes)", path, size, now))
+            }
+            Some((_, now)) if now != sha256 => Some(format!("  changed: {} (content)", path)),
+            Some(_) => None,
+        })
+        .chain(
+            current
+                .keys()
+                .filter(|path| !planned.contains_key(*path))
+                .map(|path| format!("  new: {}", path)),
+        )
+        .collect();
+    if differences.is_empty() {
+        // Only the order in which the directories were read changed.
+        return Ok(());
+    }
+    differences.sort();
+    Err(DeployError::Config(format!(
+        "The artifacts' files differ from the plan, make a new one with `plan`:\n{}",
+        differences.join("\n")
+    )))
+}
+
+/// Files found for the artifacts right now, with the hash of their content.
+/// Missing artifacts, e.g. ones the build creates, are left out.
+fn planned_files(app: &App, allow_external: bool) -> Result<Vec<PlannedFile>> {
+    let entries = collect_entries(
+        app.artifacts(),
+        app.max_artifact_size(),
+        app.max_depth(),
+        allow_external,
+    )?;
+    entries
+        .into_iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| {
+            let (_, hasher) = File::open(&entry.source)
+                .and_then(|mut file| copy_hashed(&mut file, &mut io::sink()))
+                .map_err(|err| {
+                    DeployError::Build(format!(
+                        "Error hashing artifact `{}`: {}",
+                        entry.source.display(),
+                        err
+                    ))
+                })?;
+            Ok(PlannedFile {
+                path: entry.name,
+                size: entry.size,
+                sha256: hex_digest(hasher),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,40 @@
+use crate::config::{App, Host};
+use crate::error::{DeployError, Result};
+use serde_json::{json, Value};
+
+/// Field name fragments whose values are never printed.
+const SECRET_FIELDS: [&str; 4] = ["password", "secret", "token", "sign_key"];
+
+/// Prints the resolved app and hosts as pretty JSON, with secret-looking
+/// values replaced by `"<redacted>"`. When the deploy file `extends` another,
+/// the file each app field came from is listed under `origins`.
+pub fn print_config(app: &App, hosts: &[Host]) -> Result<()> {
+    let mut config = json!({ "app": app, "hosts": hosts });
+    if !app.origins().is_empty() {
+        config["origins"] = json!(app.origins());
+    }
+    redact(&mut config);
+
+    let out = serde_json::to_string_pretty(&config)
+        .map_err(|err| DeployError::Other(format!("Error serializing config: {}", err)))?;
+    println!("{}", out);
+
+    Ok(())
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if !value.is_null() && SECRET_FIELDS.iter().any(|f| key.contains(f)) {
+                    *value = Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
@@ -0,0 +1,387 @@
+use openssh::{OwningCommand, Session, Stdio};
+use std::collections::BTreeMap;
+use std::process::{ExitStatus, Stdio as LocalStdio};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command as LocalCommand;
+use tracing::debug;
+
+use crate::config::Platform;
+use crate::events::{emit, DeployEvent};
+
+/// Where a host's remote commands run: over its SSH session, or on this
+/// machine for a `"transport": "local"` host.
+#[derive(Clone, Copy)]
+pub enum Remote<'s> {
+    Ssh(&'s Session),
+    Local,
+}
+
+impl<'s> Remote<'s> {
+    /// Commands over `session`, or local ones without one.
+    pub fn new(session: Option<&'s Session>) -> Remote<'s> {
+        session.map_or(Remote::Local, Remote::Ssh)
+    }
+
+    /// Like [`Session::command`], `program` and the [`RemoteCommand::args`]
+    /// added next are shell-escaped.
+    pub fn command(&self, program: &str) -> RemoteCommand<'s> {
+        match self {
+            Remote::Ssh(session) => RemoteCommand::Ssh(session.command(program.to_string())),
+            Remote::Local => RemoteCommand::Local(shell_words::quote(program).into_owned()),
+        }
+    }
+
+    /// Command running the shell command line `line` as is.
+    pub fn raw_command(&self, line: impl AsRef<str>) -> RemoteCommand<'s> {
+        match self {
+            Remote::Ssh(session) => RemoteCommand::Ssh(session.raw_command(line.as_ref())),
+            Remote::Local => RemoteCommand::Local(line.as_ref().to_string()),
+        }
+    }
+
+    /// Errors when the SSH session died. A local host can't drop.
+    pub async fn check(&self) -> Result<(), openssh::Error> {
+        match self {
+            Remote::Ssh(session) => session.check().await,
+            Remote::Local => Ok(()),
+        }
+    }
+}
+
+/// A command about to run through a [`Remote`]. Local ones are built as a
+/// shell command line the same way `ssh` builds the remote one, and run with
+/// `sh -c`.
+pub enum RemoteCommand<'s> {
+    Ssh(OwningCommand<&'s Session>),
+    Local(String),
+}
+
+impl RemoteCommand<'_> {
+    /// Adds `arg`, shell-escaped.
+    pub fn arg(&mut self, arg: impl AsRef<str>) -> &mut Self {
+        match self {
+            RemoteCommand::Ssh(command) => {
+                command.arg(arg);
+            }
+            RemoteCommand::Local(line) => {
+                line.push(' ');
+                line.push_str(&shell_words::quote(arg.as_ref()));
+            }
+        }
+        self
+    }
+
+    pub fn args<I, A>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<str>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Adds `arg` as is, for the shell to interpret.
+    pub fn raw_arg(&mut self, arg: impl AsRef<str>) -> &mut Self {
+        match self {
+            RemoteCommand::Ssh(command) => {
+                command.raw_arg(arg.as_ref());
+            }
+            RemoteCommand::Local(line) => {
+                line.push(' ');
+                line.push_str(arg.as_ref());
+            }
+        }
+        self
+    }
+
+    pub fn raw_args<I, A>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<str>,
+    {
+        for arg in args {
+            self.raw_arg(arg);
+        }
+        self
+    }
+}
+
+impl std::fmt::Debug for RemoteCommand<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RemoteCommand::Ssh(command) => command.fmt(f),
+            RemoteCommand::Local(line) => write!(f, "sh -c {:?}", line),
+        }
+    }
+}
+
+fn local_shell(line: &str) -> LocalCommand {
+    let mut command = LocalCommand::new("sh");
+    command.arg("-c").arg(line);
+    command
+}
+
+/// Captured result of a remote command that ran to completion.
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub status: ExitStatus,
+}
+
+impl CommandOutput {
+    /// Whether the command exited with status 0 without writing to stderr.
+    pub fn success(&self) -> bool {
+        self.status.success() && self.stderr.is_empty()
+    }
+
+    /// Turns a failed command into an `Err` carrying its exit status and
+    /// both output streams.
+    pub fn check(self) -> Result<CommandOutput, String> {
+        if self.success() {
+            return Ok(self);
+        }
+        Err(self.report())
+    }
+
+    /// Like [`CommandOutput::check`], but only the exit status decides
+    /// success, for commands that write progress to stderr.
+    pub fn check_status(self) -> Result<CommandOutput, String> {
+        if self.status.success() {
+            return Ok(self);
+        }
+        Err(self.report())
+    }
+
+    /// Exit status followed by whichever output streams aren't empty.
+    fn report(&self) -> String {
+        let mut report = format!("command exited with {}", self.status);
+        for (name, output) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            let output = output.trim_end();
+            if !output.is_empty() {
+                report.push_str(&format!("\n{}:\n{}", name, output));
+            }
+        }
+        report
+    }
+}
+
+/// Runs a remote command to completion, capturing stdout, stderr and the exit
+/// status. Only failures to spawn the command or reach the host are returned
+/// as `Err`; callers inspect the [`CommandOutput`] to decide whether the
+/// command itself failed.
+pub async fn unwrap_command_stderr(
+    command: &mut RemoteCommand<'_>,
+) -> Result<CommandOutput, String> {
+    let out = match command {
+        RemoteCommand::Ssh(command) => command.output().await.map_err(|err| err.to_string())?,
+        RemoteCommand::Local(line) => local_shell(line)
+            .output()
+            .await
+            .map_err(|err| err.to_string())?,
+    };
+    let output = CommandOutput {
+        stdout: String::from_utf8_lossy(&out.stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        status: out.status,
+    };
+    debug!(
+        status = %output.status,
+        stdout = %output.stdout,
+        stderr = %output.stderr,
+        "remote command finished"
+    );
+    Ok(output)
+}
+
+/// Like [`unwrap_command_stderr`], but for long-running commands: every line
+/// written to stdout or stderr is reported as it arrives, and captured too.
+pub async fn stream_command(command: &mut RemoteCommand<'_>) -> Result<CommandOutput, String> {
+    let output = match command {
+        RemoteCommand::Ssh(command) => {
+            let mut child = command
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .await
+                .map_err(|err| err.to_string())?;
+            let (stdout, stderr) = futures::join!(
+                forward_lines(child.stdout().take().unwrap()),
+                forward_lines(child.stderr().take().unwrap())
+            );
+            CommandOutput {
+                stdout,
+                stderr,
+                status: child.wait().await.map_err(|err| err.to_string())?,
+            }
+        }
+        RemoteCommand::Local(line) => {
+            let mut child = local_shell(line)
+                .stdin(LocalStdio::null())
+                .stdout(LocalStdio::piped())
+                .stderr(LocalStdio::piped())
+                .spawn()
+                .map_err(|err| err.to_string())?;
+            let (stdout, stderr) = futures::join!(
+                forward_lines(child.stdout.take().unwrap()),
+                forward_lines(child.stderr.take().unwrap())
+            );
+            CommandOutput {
+                stdout,
+                stderr,
+                status: child.wait().await.map_err(|err| err.to_string())?,
+            }
+        }
+    };
+    debug!(status = %output.status, "remote command finished");
+    Ok(output)
+}
+
+/// Reports each line read from `reader` until it closes, returning them all.
+async fn forward_lines(reader: impl AsyncRead + Unpin) -> String {
+    let mut reader = BufReader::new(reader);
+    let mut output = String::new();
+    let mut line = Vec::new();
+    while let Ok(n @ 1..) = reader.read_until(b'\n', &mut line).await {
+        let text = String::from_utf8_lossy(&line[..n]);
+        emit(DeployEvent::RemoteOutput {
+            line: text.trim_end_matches(['\r', '\n']),
+        });
+        output.push_str(&text);
+        line.clear();
+    }
+    output
+}
+
+/// Appends `sudo -n -u <user>` to `command` when `user` is set, so the
+/// arguments added next run as that user. `-n` makes sudo fail instead of
+/// waiting for a password nobody can type.
+pub fn as_user<'c, 's>(
+    command: &'c mut RemoteCommand<'s>,
+    user: Option<&str>,
+) -> &'c mut RemoteCommand<'s> {
+    if let Some(user) = user {
+        command.args(["sudo", "-n", "-u", user]);
+    }
+    command
+}
+
+/// Appends `env NAME=value...` to `command` when `vars` isn't empty, so the
+/// program added next runs with them set, through `sudo` or not.
+pub fn with_env<'c, 's>(
+    command: &'c mut RemoteCommand<'s>,
+    vars: &BTreeMap<String, String>,
+) -> &'c mut RemoteCommand<'s> {
+    if !vars.is_empty() {
+        command.arg("env").args(
+            vars.iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        );
+    }
+    command
+}
+
+/// Command line running `script` through PowerShell on a Windows host, whose
+/// OpenSSH server hands commands to `cmd.exe`.
+pub fn powershell(script: &str) -> String {
+    format!(
+        "powershell -NoProfile -NonInteractive -Command \"{}\"",
+        script.replace('"', "\\\"")
+    )
+}
+
+/// `path` with a leading `~` replaced by the remote user's home directory,
+/// which SFTP doesn't expand, or `None` when there is no `~` to expand.
+pub async fn expand_home(
+    session: &Remote<'_>,
+    platform: Platform,
+    path: &str,
+) -> Result<Option<String>, String> {
+    let Some(rest) = after_home(path) else {
+        return Ok(None);
+    };
+
+    let mut command = match platform {
+        Platform::Unix => session.raw_command("echo $HOME"),
+        Platform::Windows => session.raw_command(powershell("$env:USERPROFILE")),
+    };
+    let out = unwrap_command_stderr(&mut command)
+        .await
+        .map_err(|err| format!("Error reading the home directory on host: {}", err))?
+        .check()
+        .map_err(|err| format!("Error reading the home directory on host:\n{}", err))?;
+    let home = out.stdout.trim_end_matches(['\r', '\n']);
+    if home.is_empty() {
+        return Err("Error reading the home directory on host: it's empty".to_string());
+    }
+    debug!(home, "expanded `~` in host path");
+    Ok(Some(under_home(home, rest)))
+}
+
+/// The rest of `path` after a leading `~` standing for the home directory,
+/// as in `~` or `~/app`, `None` for any other path.
+fn after_home(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix('~')?;
+    (rest.is_empty() || rest.starts_with(['/', '\\'])).then_some(rest)
+}
+
+/// The `rest` of a `~` path put under `home`, as the host printed it.
+fn under_home(home: &str, rest: &str) -> String {
+    format!("{}{}", home.trim_end_matches(['/', '\\']), rest)
+}
+
+/// Quotes `value` as a PowerShell literal string.
+pub fn ps_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tilde_prefixed_path_is_expanded() {
+        let rest = after_home("~/app").unwrap();
+        assert_eq!(rest, "/app");
+        assert_eq!(under_home("/home/ec2-user", rest), "/home/ec2-user/app");
+        // A trailing separator in the home directory isn't doubled.
+        assert_eq!(under_home("/home/ec2-user/", rest), "/home/ec2-user/app");
+    }
+
+    #[test]
+    fn bare_tilde_is_the_home_directory() {
+        let rest = after_home("~").unwrap();
+        assert_eq!(under_home("/home/ec2-user", rest), "/home/ec2-user");
+    }
+
+    #[test]
+    fn windows_home_is_joined_with_backslashes() {
+        let rest = after_home("~\\app").unwrap();
+        assert_eq!(
+            under_home("C:\\Users\\deploy", rest),
+            "C:\\Users\\deploy\\app"
+        );
+    }
+
+    #[test]
+    fn paths_without_a_leading_tilde_are_left_alone() {
+        for path in ["/srv/app", "app/~", "~other/app"] {
+            assert_eq!(after_home(path), None, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn home_is_read_from_a_local_host() {
+        let expanded = expand_home(&Remote::Local, Platform::Unix, "~/app")
+            .await
+            .unwrap();
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            expanded,
+            Some(format!("{}/app", home.trim_end_matches('/')))
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use tracing::debug;
+
+use crate::config::{App, Platform};
+use crate::error::{DeployError, Result};
+use crate::remote::{unwrap_command_stderr, Remote};
+use crate::transfer::RemoteFs;
+
+/// Tarball of `host_path` taken inside it before a deploy with
+/// `"on_failure": "rollback"`, removed again once the deploy is over.
+pub const BACKUP_NAME: &str = ".ec2-deploy-backup.tar";
+
+/// `host_path` as it was before the deploy.
+#[derive(Debug)]
+pub struct Backup {
+    path: String,
+}
+
+/// Tars the contents of `host_path` into [`BACKUP_NAME`]. `None` when
+/// `host_path` doesn't exist yet, a first deploy having nothing to roll back
+/// to.
+pub async fn backup(session: &Remote<'_>, app: &App) -> Result<Option<Backup>> {
+    let exists = unwrap_command_stderr(session.command("test").args(["-d", app.host_path()]))
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `test` on host: {}", err)))?
+        .success();
+    if !exists {
+        debug!(path = %app.host_path(), "no host_path yet, nothing to back up");
+        return Ok(None);
+    }
+
+    let path = Platform::Unix.join(app.host_path(), BACKUP_NAME);
+    unwrap_command_stderr(session.command("tar").args([
+        "-cf",
+        &path,
+        "-C",
+        app.host_path(),
+        &format!("--exclude=./{}", BACKUP_NAME),
+        ".",
+    ]))
+    .await
+    .map_err(|err| DeployError::Remote(format!("Error running `tar` on host: {}", err)))?
+    .check()
+    .map_err(|err| {
+        DeployError::Remote(format!(
+            "Error backing up `{}` before the deploy:\n{}",
+            app.host_path(),
+            err
+        ))
+    })?;
+    debug!(%path, "host_path backed up");
+    Ok(Some(Backup { path }))
+}
+
+/// Empties `host_path` and extracts `backup` back into it, then removes it.
+pub async fn restore(session: &Remote<'_>, app: &App, backup: Backup) -> Result<()> {
+    let mut restore = session.command("find");
+    restore
+        .args([
+            app.host_path(),
+            "-mindepth",
+            "1",
+            "-maxdepth",
+            "1",
+            "!",
+            "-name",
+            BACKUP_NAME,
+            "-exec",
+            "rm",
+            "-rf",
+            "{}",
+            "+",
+        ])
+        .raw_arg("&&")
+        .args(["tar", "-xf", &backup.path, "-C", app.host_path()])
+        .raw_arg("&&")
+        .args(["rm", "-f", &backup.path]);
+    unwrap_command_stderr(&mut restore)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `tar` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error restoring `{}` from `{}`:\n{}",
+                app.host_path(),
+                backup.path,
+                err
+            ))
+        })?;
+    Ok(())
+}
+
+/// Removes `backup` after a successful deploy. Failing to is only logged,
+/// the next backup replacing it anyway.
+pub async fn discard(fs: &RemoteFs<'_>, backup: Backup) {
+    if let Err(err) = fs.remove(&backup.path).await {
+        debug!(path = %backup.path, %err, "backup not removed");
+    }
+}
@@ -0,0 +1,46 @@
+use crate::cli::SchemaFile;
+use crate::config::{App, Host};
+use crate::error::{DeployError, Result};
+use schemars::{json_schema, schema_for};
+
+/// Prints a JSON Schema for `file`, generated from the config types so it
+/// always matches what the tool accepts.
+pub fn schema(file: SchemaFile) -> Result<()> {
+    let schema = match file {
+        SchemaFile::Deploy => {
+            let mut schema = schema_for!(App);
+            // `extends` and `profiles` are merged away before the app is
+            // parsed, so they have no field of their own.
+            if let Some(properties) = schema
+                .get_mut("properties")
+                .and_then(|properties| properties.as_object_mut())
+            {
+                properties.insert(
+                    "extends".to_string(),
+                    json_schema!({
+                        "description": "Deploy file whose fields this one inherits and overrides, relative to this file",
+                        "type": "string"
+                    })
+                    .into(),
+                );
+                properties.insert(
+                    "profiles".to_string(),
+                    json_schema!({
+                        "description": "Named sets of fields replacing the base ones, selected with `--profile`",
+                        "type": "object",
+                        "additionalProperties": { "type": "object" }
+                    })
+                    .into(),
+                );
+            }
+            schema
+        }
+        SchemaFile::Hosts => schema_for!(Vec<Host>),
+    };
+
+    let out = serde_json::to_string_pretty(&schema)
+        .map_err(|err| DeployError::Other(format!("Error serializing schema: {}", err)))?;
+    println!("{}", out);
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+use std::process::Command;
+
+use tracing::{debug, warn};
+
+use crate::config::{App, Platform};
+use crate::error::{DeployError, Result};
+use crate::events::{emit, DeployEvent};
+use crate::remote::{unwrap_command_stderr, Remote};
+use crate::transfer::RemoteFs;
+use crate::upload;
+
+/// Detached minisign signature written next to `path`.
+pub fn signature_path(path: &str) -> String {
+    format!("{}.minisig", path)
+}
+
+/// Signs the archive with the app's `sign_key` through the local `minisign`,
+/// which asks for the key's password when it has one.
+pub fn sign(app: &App, key: &str) -> Result<()> {
+    let signature = signature_path(app.archive_path());
+    let status = Command::new("minisign")
+        .args(["-S", "-s", key, "-m", app.archive_path(), "-x", &signature])
+        .status()
+        .map_err(|err| DeployError::Build(format!("Error running `minisign`: {}", err)))?;
+    if !status.success() {
+        return Err(DeployError::Build(format!(
+            "Error signing `{}`: `minisign` exited with {}",
+            app.archive_name(),
+            status
+        )));
+    }
+    emit(DeployEvent::ArchiveSigned { path: &signature });
+    Ok(())
+}
+
+/// Uploads the archive's signature next to it. On unix hosts with `minisign`
+/// installed, the archive is then checked against `sign_public_key` when
+/// set, failing the deploy before extraction on a mismatch.
+pub async fn upload_signature(
+    session: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    app: &App,
+    platform: Platform,
+    host_output_path: &str,
+) -> Result<()> {
+    let local = signature_path(app.archive_path());
+    let remote = signature_path(host_output_path);
+    fs.upload(Path::new(&local), &remote, None, upload::CHUNK_SIZE)
+        .await
+        .map_err(|err| {
+            DeployError::Remote(format!("Error writing signature into host's fs: {}", err))
+        })?;
+    debug!(path = %remote, "signature written");
+
+    let Some(public_key) = app.sign_public_key() else {
+        debug!("no `sign_public_key`, not verifying the signature on the host");
+        return Ok(());
+    };
+    if platform == Platform::Windows {
+        debug!("not verifying the signature on a windows host");
+        return Ok(());
+    }
+    let found = unwrap_command_stderr(session.command("command").args(["-v", "minisign"]))
+        .await
+        .is_ok_and(|out| out.success());
+    if !found {
+        warn!("`minisign` not found on the host, the archive signature isn't verified");
+        return Ok(());
+    }
+
+    unwrap_command_stderr(session.command("minisign").args([
+        "-V",
+        "-q",
+        "-P",
+        public_key,
+        "-m",
+        host_output_path,
+        "-x",
+        &remote,
+    ]))
+    .await
+    .map_err(|err| DeployError::Remote(format!("Error running `minisign` on host: {}", err)))?
+    .check_status()
+    .map_err(|err| {
+        DeployError::Remote(format!(
+            "Signature of `{}` doesn't verify on the host:\n{}",
+            host_output_path, err
+        ))
+    })?;
+    emit(DeployEvent::SignatureVerified);
+    Ok(())
+}
@@ -0,0 +1,300 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use openssh::{KnownHosts, Session, SessionBuilder};
+use tempfile::TempDir;
+
+use crate::config::{Host, KnownHostsPolicy, Transport};
+use crate::error::{DeployError, Result};
+
+/// Private temporary directory holding the ssh config and control socket for
+/// a host's `ssh_options`, `ssh_binary` and `host_key`. `openssh` has no way
+/// to add raw options, so the options go into a config file that includes
+/// the user's own. It always runs `ssh` from `PATH`, so with `ssh_binary`
+/// the control master is launched here instead, see [`SshOverrides::launch`].
+/// Dropping it stops that master and removes the directory, so it must
+/// outlive the session. A pinned `host_key` is written to a `known_hosts`
+/// file of its own, the only one ssh is pointed at.
+pub struct SshOverrides {
+    dir: TempDir,
+    config: Option<PathBuf>,
+    known_hosts: Option<PathBuf>,
+    binary: Option<PathBuf>,
+}
+
+impl SshOverrides {
+    /// Returns `None` when the host uses plain `ssh` with no extra options.
+    pub fn prepare(host: &Host) -> Result<Option<SshOverrides>> {
+        if host.transport() == Transport::Local
+            || host.ssh_options().is_empty()
+                && host.ssh_binary().is_none()
+                && host.host_key().is_none()
+        {
+            return Ok(None);
+        }
+
+        if let Some(option) = host.ssh_options().iter().find(|o| o.contains(['\n', '\r'])) {
+            return Err(DeployError::Config(format!(
+                "Invalid ssh option `{}`: options must be on a single line",
+                option.escape_debug()
+            )));
+        }
+
+        let dir = tempfile::Builder::new()
+            .prefix("ec2-deploy-ssh-")
+            .tempdir()
+            .map_err(|err| {
+                DeployError::Other(format!("Error creating a temporary ssh directory: {}", err))
+            })?;
+        let mut overrides = SshOverrides {
+            dir,
+            config: None,
+            known_hosts: None,
+            binary: None,
+        };
+
+        let mut options = host.ssh_options().clone();
+        if let Some(key) = host.host_key() {
+            let known_hosts = overrides.dir.path().join("known_hosts");
+            let content = pinned_known_hosts(host, key, overrides.dir.path())?;
+            fs::write(&known_hosts, content).map_err(|err| {
+                DeployError::Other(format!(
+                    "Error writing `{}`: {}",
+                    known_hosts.display(),
+                    err
+                ))
+            })?;
+            overrides.known_hosts = Some(known_hosts);
+            options.push("GlobalKnownHostsFile=/dev/null".to_string());
+        }
+
+        if !options.is_empty() {
+            // ssh keeps the first value it reads for each option, so these
+            // take precedence over the included defaults.
+            let mut content = options.join("\n");
+            content.push_str("\nInclude ~/.ssh/config\nInclude /etc/ssh/ssh_config\n");
+
+            let config = overrides.dir.path().join("config");
+            fs::write(&config, content).map_err(|err| {
+                DeployError::Other(format!("Error writing `{}`: {}", config.display(), err))
+            })?;
+            overrides.config = Some(config);
+        }
+
+        if let Some(binary) = host.ssh_binary() {
+            let binary = binary.canonicalize().map_err(|err| {
+                DeployError::Config(format!(
+                    "Invalid ssh binary `{}`: {}",
+                    binary.display(),
+                    err
+                ))
+            })?;
+            overrides.binary = Some(binary);
+        }
+
+        Ok(Some(overrides))
+    }
+
+    pub fn apply(&self, builder: &mut SessionBuilder) {
+        if let Some(config) = &self.config {
+            builder.config_file(config);
+        }
+        if let Some(known_hosts) = &self.known_hosts {
+            builder
+                .user_known_hosts_file(known_hosts)
+                .known_hosts_check(KnownHosts::Strict);
+        }
+    }
+
+    /// Connects to `host` with `ssh_binary` as the control master, `None`
+    /// without one. The master gets the options `SessionBuilder` would pass
+    /// and the session is resumed on its socket, so the process environment
+    /// is left alone; each command is a short-lived `ssh` from `PATH`
+    /// forwarding over that socket.
+    pub async fn launch(
+        &self,
+        host: &Host,
+    ) -> Option<std::result::Result<Session, openssh::Error>> {
+        let binary = self.binary.as_ref()?;
+        let ctl = self.ctl();
+        let log = self.dir.path().join("log");
+        // ssh appends to the log, which should only hold this attempt.
+        let _ = fs::remove_file(&log);
+
+        let checking = match (&self.known_hosts, host.known_hosts()) {
+            (Some(_), _) | (None, KnownHostsPolicy::Strict) => "yes",
+            (None, KnownHostsPolicy::Add) => "accept-new",
+            (None, KnownHostsPolicy::Accept) => "no",
+        };
+        let mut master = tokio::process::Command::new(binary);
+        master
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .arg("-E")
+            .arg(&log)
+            .arg("-S")
+            .arg(&ctl)
+            .args(["-M", "-f", "-N", "-o", "ControlPersist=yes"])
+            .args(["-o", "BatchMode=yes", "-o"])
+            .arg(format!("StrictHostKeyChecking={}", checking))
+            .args(["-p", &host.port().to_string(), "-l", host.user()])
+            .args(["-o", "IdentitiesOnly=yes", "-i", host.key_path()]);
+        if let Some(interval) = host.keepalive() {
+            master
+                .arg("-o")
+                .arg(format!("ServerAliveInterval={}", interval.as_secs()));
+        }
+        if host.ssh_compression() {
+            master.args(["-o", "Compression=yes"]);
+        }
+        if let Some(config) = &self.config {
+            master.arg("-F").arg(config);
+        }
+        if let Some(known_hosts) = &self.known_hosts {
+            master
+                .arg("-o")
+                .arg(format!("UserKnownHostsFile={}", known_hosts.display()));
+        }
+        master.arg(host.address());
+
+        // The master forks into the background once connected.
+        let status = match master.status().await {
+            Ok(status) => status,
+            Err(err) => return Some(Err(openssh::Error::Connect(err))),
+        };
+        if !status.success() {
+            let output = fs::read_to_string(&log).unwrap_or_default();
+            let message = output.trim().strip_prefix("ssh: ").unwrap_or(output.trim());
+            return Some(Err(openssh::Error::Connect(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                message.to_string(),
+            ))));
+        }
+        Some(Ok(Session::resume(
+            ctl.into_boxed_path(),
+            Some(log.into_boxed_path()),
+        )))
+    }
+
+    fn ctl(&self) -> PathBuf {
+        self.dir.path().join("master")
+    }
+}
+
+/// `known_hosts` line for the host's pinned `key`. A full `<type> <base64>`
+/// key is used as is. For a bare key or a fingerprint the host's keys are
+/// fetched with `ssh-keyscan` and the matching one is kept, erroring when
+/// none matches.
+fn pinned_known_hosts(host: &Host, key: &str, dir: &Path) -> Result<String> {
+    let name = match host.port() {
+        22 => host.address().to_string(),
+        port => format!("[{}]:{}", host.address(), port),
+    };
+    if let [kind, base64, ..] = key.split_whitespace().collect::<Vec<_>>().as_slice() {
+        return Ok(format!("{} {} {}\n", name, kind, base64));
+    }
+
+    let scanned = keyscan(host)?;
+    let matching = if key.starts_with("SHA256:") {
+        let fingerprints = fingerprints(&scanned, dir)?;
+        scanned
+            .iter()
+            .zip(fingerprints)
+            .find(|(_, fingerprint)| fingerprint == key)
+            .map(|(line, _)| line)
+    } else {
+        scanned
+            .iter()
+            .find(|line| line.split_whitespace().nth(2) == Some(key))
+    };
+    let line = matching.ok_or_else(|| {
+        DeployError::Config(format!(
+            "Host key of `{}` doesn't match its pinned `host_key`",
+            host.name()
+        ))
+    })?;
+    let key = line
+        .split_whitespace()
+        .skip(1)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(format!("{} {}\n", name, key))
+}
+
+/// Public keys the host offers, as `known_hosts` lines.
+fn keyscan(host: &Host) -> Result<Vec<String>> {
+    let out = Command::new("ssh-keyscan")
+        .args(["-T", "10", "-p", &host.port().to_string(), host.address()])
+        .output()
+        .map_err(|err| DeployError::Other(format!("Error running `ssh-keyscan`: {}", err)))?;
+    let lines: Vec<String> = String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if lines.is_empty() {
+        let mut message = format!(
+            "No host keys read from `{}` with `ssh-keyscan`",
+            host.name()
+        );
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        if !stderr.trim().is_empty() {
+            message = format!("{}: {}", message, stderr.trim_end());
+        }
+        return Err(DeployError::Connection(message));
+    }
+    Ok(lines)
+}
+
+/// SHA-256 fingerprint of each `known_hosts` line, in order, as computed by
+/// `ssh-keygen`.
+fn fingerprints(lines: &[String], dir: &Path) -> Result<Vec<String>> {
+    let scanned = dir.join("scanned");
+    fs::write(&scanned, lines.join("\n") + "\n").map_err(|err| {
+        DeployError::Other(format!("Error writing `{}`: {}", scanned.display(), err))
+    })?;
+    let out = Command::new("ssh-keygen")
+        .args(["-E", "sha256", "-l", "-f"])
+        .arg(&scanned)
+        .output()
+        .map_err(|err| DeployError::Other(format!("Error running `ssh-keygen`: {}", err)))?;
+    if !out.status.success() {
+        return Err(DeployError::Other(format!(
+            "Error fingerprinting host keys: {}",
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect())
+}
+
+impl Drop for SshOverrides {
+    /// Stops a master left running by [`SshOverrides::launch`], which a
+    /// resumed session doesn't do on drop. The directory itself is removed
+    /// by its [`TempDir`].
+    fn drop(&mut self) {
+        let (Some(binary), ctl) = (&self.binary, self.ctl()) else {
+            return;
+        };
+        if ctl.exists() {
+            let _ = Command::new(binary)
+                .arg("-S")
+                .arg(&ctl)
+                .args(["-O", "exit", "none"])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+}
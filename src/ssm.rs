@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Prefix of hosts config values read from AWS SSM Parameter Store, as in
+/// `"host": "ssm:/prod/app/host"`.
+const PREFIX: &str = "ssm:";
+
+/// Replaces every `ssm:<name>` string in `value` with the value of that
+/// parameter, decrypted when it's a `SecureString`, and a `port` with the
+/// number it holds. Credentials and region come from the usual AWS
+/// environment. Needs the `aws` feature, without which any `ssm:` value is
+/// an error.
+pub fn resolve(value: &mut Value) -> Result<(), String> {
+    let mut names = Vec::new();
+    collect(value, &mut names);
+    if names.is_empty() {
+        return Ok(());
+    }
+    names.sort();
+    names.dedup();
+
+    let values = fetch(names)?;
+    replace(value, None, &values)
+}
+
+fn collect(value: &Value, names: &mut Vec<String>) {
+    match value {
+        Value::String(s) => {
+            if let Some(name) = s.strip_prefix(PREFIX) {
+                names.push(name.to_string());
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|value| collect(value, names)),
+        Value::Object(map) => map.values().for_each(|value| collect(value, names)),
+        _ => {}
+    }
+}
+
+fn replace(
+    value: &mut Value,
+    key: Option<&str>,
+    params: &HashMap<String, String>,
+) -> Result<(), String> {
+    match value {
+        Value::String(s) => {
+            let Some(name) = s.strip_prefix(PREFIX) else {
+                return Ok(());
+            };
+            let resolved = &params[name];
+            *value = match key {
+                Some("port") => resolved.parse::<u16>().map(Value::from).map_err(|_| {
+                    format!(
+                        "SSM parameter `{}` holds `{}`, expected a port number",
+                        name, resolved
+                    )
+                })?,
+                _ => Value::String(resolved.clone()),
+            };
+        }
+        Value::Array(values) => {
+            for value in values {
+                replace(value, None, params)?;
+            }
+        }
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                replace(value, Some(key), params)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(feature = "aws")]
+fn fetch(names: Vec<String>) -> Result<HashMap<String, String>, String> {
+    // Hosts are loaded from synchronous code on the multi-threaded runtime.
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(fetch_all(names)))
+}
+
+#[cfg(feature = "aws")]
+async fn fetch_all(names: Vec<String>) -> Result<HashMap<String, String>, String> {
+    use aws_sdk_ssm::error::DisplayErrorContext;
+
+    let config = aws_config::load_from_env().await;
+    let client = aws_sdk_ssm::Client::new(&config);
+
+    let mut values = HashMap::new();
+    // `GetParameters` takes at most 10 names per call.
+    for batch in names.chunks(10) {
+        let out = client
+            .get_parameters()
+            .set_names(Some(batch.to_vec()))
+            .with_decryption(true)
+            .send()
+            .await
+            .map_err(|err| {
+                format!(
+                    "Error reading SSM parameters: {}",
+                    DisplayErrorContext(&err)
+                )
+            })?;
+        if let Some(name) = out.invalid_parameters().first() {
+            return Err(format!("SSM parameter `{}` not found", name));
+        }
+        for parameter in out.parameters() {
+            if let (Some(name), Some(value)) = (parameter.name(), parameter.value()) {
+                values.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+
+    match names.iter().find(|name| !values.contains_key(*name)) {
+        Some(name) => Err(format!("SSM parameter `{}` has no value", name)),
+        None => Ok(values),
+    }
+}
+
+#[cfg(not(feature = "aws"))]
+fn fetch(names: Vec<String>) -> Result<HashMap<String, String>, String> {
+    Err(format!(
+        "`{}{}` needs ec2-deploy built with the `aws` feature",
+        PREFIX, names[0]
+    ))
+}
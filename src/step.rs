@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use crate::cleanup::TempDir;
+use crate::cli::{Cli, Step};
+use crate::config::{App, Host};
+use crate::error::{DeployError, Result};
+use crate::events::{emit, DeployEvent};
+use crate::remote::Remote;
+use crate::signing;
+use crate::ssh::SshOverrides;
+use crate::transfer::RemoteFs;
+use crate::{
+    build, close_session, extract, host_output_path, open_session, restart, upload,
+    with_added_artifacts, with_home_expanded, zip_artifacts,
+};
+
+/// Runs the single deploy phase `step`, locally for `build` and on each of
+/// `hosts` in turn otherwise, stopping at the first failure. Nothing is
+/// recorded, neither the deploy markers nor the history.
+pub async fn run(cli: &Cli, app: &App, hosts: &[Host], step: Step) -> Result<()> {
+    if step == Step::Build {
+        build(app, cli.shell_build).await?;
+        return Ok(());
+    }
+
+    let _archive_dir;
+    let archive_app;
+    let app = if step == Step::Upload {
+        let dir = TempDir::new("archive").map_err(|err| {
+            DeployError::Build(format!("Error creating the archive's directory: {}", err))
+        })?;
+        archive_app = app.with_archive_dir(dir.path());
+        zip(cli, &archive_app).await?;
+        _archive_dir = dir;
+        &archive_app
+    } else {
+        app
+    };
+
+    for host in hosts {
+        let ssh = SshOverrides::prepare(host)?;
+        let session = open_session(host, ssh.as_ref()).await?;
+        let result = run_on_session(cli, app, host, &Remote::new(session.as_ref()), step).await;
+        close_session(session).await;
+        result.map_err(|err| err.context(format!("Running `{}` on `{}`", step, host.name())))?;
+
+        emit(DeployEvent::StepRan {
+            step,
+            host: host.name(),
+        });
+    }
+    Ok(())
+}
+
+/// Zips the artifacts as they are now into the app's archive, signing it
+/// when the app has a `sign_key`.
+async fn zip(cli: &Cli, app: &App) -> Result<()> {
+    let prepared = with_added_artifacts(cli, app, None).await?;
+    let app = &prepared.app;
+    zip_artifacts(app, cli)?;
+    if let Some(key) = app.sign_key() {
+        signing::sign(app, key)?;
+    }
+    Ok(())
+}
+
+async fn run_on_session(
+    cli: &Cli,
+    app: &App,
+    host: &Host,
+    remote: &Remote<'_>,
+    step: Step,
+) -> Result<()> {
+    let fs = RemoteFs::open(*remote, host).await?;
+    let result = run_with_fs(cli, app, host, remote, &fs, step).await;
+    fs.close().await;
+    result
+}
+
+async fn run_with_fs(
+    cli: &Cli,
+    app: &App,
+    host: &Host,
+    remote: &Remote<'_>,
+    fs: &RemoteFs<'_>,
+    step: Step,
+) -> Result<()> {
+    let expanded_app;
+    let app = match with_home_expanded(remote, app, host.platform()).await? {
+        Some(app) => {
+            expanded_app = app;
+            &expanded_app
+        }
+        None => app,
+    };
+    let platform = host.platform();
+    let host_output_path = host_output_path(app, platform);
+
+    match step {
+        Step::Build => unreachable!("the build runs locally"),
+        Step::Upload => {
+            let local = Path::new(app.archive_path());
+            upload(remote, fs, cli, app, host, local, &host_output_path).await?;
+            if app.sign_key().is_some() {
+                signing::upload_signature(remote, fs, app, platform, &host_output_path).await?;
+            }
+            Ok(())
+        }
+        Step::Extract => extract(remote, app, platform, &host_output_path).await,
+        Step::Restart => restart(remote, fs, app, host, cli.force).await,
+    }
+}
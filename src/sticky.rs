@@ -0,0 +1,69 @@
+use std::io::IsTerminal;
+
+use dialoguer::Confirm;
+use tracing::{debug, instrument};
+
+use crate::config::App;
+use crate::error::{DeployError, Result};
+use crate::transfer::RemoteFs;
+
+/// Compares each of the app's sticky files with the copy already on the host.
+/// Any difference is confirmed interactively, or aborts the deploy when there
+/// is no terminal to ask on. Files missing on either side are skipped.
+#[instrument(skip_all)]
+pub async fn check_sticky_files(fs: &RemoteFs<'_>, app: &App) -> Result<()> {
+    let mut changed = Vec::new();
+
+    for path in app.sticky_files() {
+        let Some(local) = app.local_source(path) else {
+            debug!(path, "sticky file not among artifacts");
+            continue;
+        };
+        let local = std::fs::read(&local).map_err(|err| {
+            DeployError::Other(format!("Error reading `{}`: {}", local.display(), err))
+        })?;
+
+        let remote_path = format!("{}/{}", app.host_path(), path);
+        let remote = match fs.read(&remote_path).await {
+            Ok(Some(remote)) => remote,
+            Ok(None) => {
+                debug!(path, "sticky file not on host yet");
+                continue;
+            }
+            Err(err) => {
+                return Err(DeployError::Remote(format!(
+                    "Error reading `{}` on host: {}",
+                    remote_path, err
+                )))
+            }
+        };
+
+        if remote[..] != local[..] {
+            changed.push(path.as_str());
+        }
+    }
+
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    let list = changed.join(", ");
+    let interactive = std::io::stdout().is_terminal() && std::io::stdin().is_terminal();
+    if !interactive {
+        return Err(DeployError::Remote(format!(
+            "Deploy would overwrite host-specific files that differ locally: {}\n\
+             Pass `--overwrite-config` to replace them anyway",
+            list
+        )));
+    }
+
+    let proceed = Confirm::new()
+        .with_prompt(format!("{} differ from the host's copy. Overwrite?", list))
+        .default(false)
+        .interact()
+        .map_err(|err| DeployError::Other(format!("Error reading confirmation: {}", err)))?;
+    if !proceed {
+        return Err(DeployError::Other("Deploy aborted".to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use openssh::{RemoteChild, Session, Stdio};
+use openssh_sftp_client::error::SftpErrorKind;
+use openssh_sftp_client::metadata::Permissions;
+use openssh_sftp_client::{Error as SftpError, Sftp};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use tracing::{instrument, warn};
+
+use crate::config::{Host, Platform, Transfer};
+use crate::error::{DeployError, Result};
+use crate::remote::{unwrap_command_stderr, Remote};
+use crate::upload::{retry_write, scp_file, upload_file};
+
+/// File access on the host, through the SFTP subsystem or, with
+/// `"transfer": "scp"`, through `scp` and shell commands on the session. A
+/// `"transport": "local"` host's files are accessed directly.
+pub enum RemoteFs<'s> {
+    Sftp {
+        /// The subsystem, which must outlive the client.
+        _child: Box<RemoteChild<'s>>,
+        sftp: Sftp,
+        /// The host's `sftp.write_retries`.
+        write_retries: u32,
+    },
+    Scp(&'s Session),
+    Local,
+}
+
+impl<'s> RemoteFs<'s> {
+    #[instrument(name = "connect", skip_all)]
+    pub async fn open(session: Remote<'s>, host: &Host) -> Result<RemoteFs<'s>> {
+        let Remote::Ssh(session) = session else {
+            return Ok(RemoteFs::Local);
+        };
+        match host.transfer() {
+            Transfer::Sftp => open_sftp(session, host).await,
+            Transfer::Scp if host.platform() == Platform::Windows => Err(DeployError::Config(
+                "`\"transfer\": \"scp\"` is only supported on unix hosts".to_string(),
+            )),
+            Transfer::Scp => Ok(RemoteFs::Scp(session)),
+        }
+    }
+
+    /// Streams the local file at `local` into `remote`, see [`upload_file`].
+    pub async fn upload(
+        &self,
+        local: &Path,
+        remote: &str,
+        limit: Option<u64>,
+        chunk_size: usize,
+    ) -> Result<u64, String> {
+        match self {
+            RemoteFs::Sftp {
+                sftp,
+                write_retries,
+                ..
+            } => upload_file(sftp, local, remote, limit, chunk_size, *write_retries).await,
+            RemoteFs::Scp(session) => scp_file(session, local, remote, limit, chunk_size).await,
+            RemoteFs::Local => tokio::fs::copy(local, remote)
+                .await
+                .map_err(|err| format!("Error copying to `{}`: {}", remote, err)),
+        }
+    }
+
+    /// Content of `remote`, `None` when it doesn't exist.
+    pub async fn read(&self, remote: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => match sftp.fs().read(remote).await {
+                Ok(content) => Ok(Some(content.to_vec())),
+                Err(SftpError::SftpError(SftpErrorKind::NoSuchFile, _)) => Ok(None),
+                Err(err) => Err(err.to_string()),
+            },
+            RemoteFs::Scp(session) => {
+                if !test(session, &["-e", remote]).await? {
+                    return Ok(None);
+                }
+                let out = session
+                    .command("cat")
+                    .arg(remote)
+                    .output()
+                    .await
+                    .map_err(|err| err.to_string())?;
+                if !out.status.success() {
+                    return Err(format!(
+                        "`cat` exited with {}: {}",
+                        out.status,
+                        String::from_utf8_lossy(&out.stderr).trim_end()
+                    ));
+                }
+                Ok(Some(out.stdout))
+            }
+            RemoteFs::Local => match tokio::fs::read(remote).await {
+                Ok(content) => Ok(Some(content)),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.to_string()),
+            },
+        }
+    }
+
+    /// Replaces the content of `remote` with `content`, creating it if needed.
+    /// Over SFTP the write is retried like upload chunks on transient errors.
+    pub async fn write(&self, remote: &str, content: &[u8]) -> Result<(), String> {
+        match self {
+            RemoteFs::Sftp {
+                sftp,
+                write_retries,
+                ..
+            } => {
+                let mut attempt = 0;
+                loop {
+                    match sftp.fs().write(remote, content).await {
+                        Ok(()) => return Ok(()),
+                        Err(err) if retry_write(&err, &mut attempt, *write_retries).await => {}
+                        Err(err) => return Err(err.to_string()),
+                    }
+                }
+            }
+            RemoteFs::Scp(session) => {
+                let mut child = session
+                    .command("tee")
+                    .arg(remote)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .await
+                    .map_err(|err| format!("Error starting `tee` on host: {}", err))?;
+                let mut stdin = child.stdin().take().unwrap();
+                stdin
+                    .write_all(content)
+                    .await
+                    .map_err(|err| format!("Error writing `{}` on host: {}", remote, err))?;
+                drop(stdin);
+                let out = child
+                    .wait_with_output()
+                    .await
+                    .map_err(|err| format!("Error waiting for `tee` on host: {}", err))?;
+                if !out.status.success() {
+                    return Err(format!(
+                        "`tee` exited with {}: {}",
+                        out.status,
+                        String::from_utf8_lossy(&out.stderr).trim_end()
+                    ));
+                }
+                Ok(())
+            }
+            RemoteFs::Local => tokio::fs::write(remote, content)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Whether `remote` exists and isn't a directory.
+    pub async fn is_file(&self, remote: &str) -> Result<bool, String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => match sftp.fs().metadata(remote).await {
+                Ok(metadata) => Ok(metadata.file_type().is_some_and(|kind| !kind.is_dir())),
+                Err(SftpError::SftpError(SftpErrorKind::NoSuchFile, _)) => Ok(false),
+                Err(err) => Err(err.to_string()),
+            },
+            RemoteFs::Scp(session) => Ok(
+                test(session, &["-e", remote]).await? && !test(session, &["-d", remote]).await?
+            ),
+            RemoteFs::Local => match tokio::fs::metadata(remote).await {
+                Ok(metadata) => Ok(!metadata.is_dir()),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+                Err(err) => Err(err.to_string()),
+            },
+        }
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<(), String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => sftp
+                .fs()
+                .rename(from, to)
+                .await
+                .map_err(|err| err.to_string()),
+            RemoteFs::Scp(session) => run(session, "mv", &["-f", from, to]).await,
+            RemoteFs::Local => tokio::fs::rename(from, to)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    pub async fn remove(&self, remote: &str) -> Result<(), String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => sftp
+                .fs()
+                .remove_file(remote)
+                .await
+                .map_err(|err| err.to_string()),
+            RemoteFs::Scp(session) => run(session, "rm", &[remote]).await,
+            RemoteFs::Local => tokio::fs::remove_file(remote)
+                .await
+                .map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Sets the permission bits of `remote` to `mode`. Over SFTP the mode is
+    /// read back, since servers may silently ignore parts of it.
+    pub async fn set_mode(&self, remote: &str, mode: u16) -> Result<(), String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => {
+                let mut fs = sftp.fs();
+                let permissions = Permissions::from(mode);
+                fs.set_permissions(remote, permissions)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                let metadata = fs.metadata(remote).await.map_err(|err| err.to_string())?;
+                if metadata.permissions() != Some(permissions) {
+                    return Err(format!(
+                        "`{}` has a different mode after setting it",
+                        remote
+                    ));
+                }
+                Ok(())
+            }
+            RemoteFs::Scp(session) => {
+                run(session, "chmod", &[&format!("{:o}", mode), remote]).await
+            }
+            RemoteFs::Local => set_local_mode(remote, u32::from(mode)).await,
+        }
+    }
+
+    /// Adds the execute bit for the owner and group of `remote`, keeping the
+    /// rest of its mode, or `644` when the server doesn't report one.
+    pub async fn make_executable(&self, remote: &str) -> Result<(), String> {
+        match self {
+            RemoteFs::Sftp { sftp, .. } => {
+                let mut fs = sftp.fs();
+                let permissions = fs
+                    .metadata(remote)
+                    .await
+                    .map_err(|err| err.to_string())?
+                    .permissions()
+                    .unwrap_or(Permissions::from(0o644));
+                fs.set_permissions(remote, with_execute_bits(permissions))
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            RemoteFs::Scp(session) => run(session, "chmod", &["ug+x", remote]).await,
+            RemoteFs::Local => {
+                let metadata = tokio::fs::metadata(remote)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                set_local_mode(remote, metadata.permissions().mode() | 0o110).await
+            }
+        }
+    }
+
+    pub async fn close(self) {
+        if let RemoteFs::Sftp { sftp, .. } = self {
+            if let Err(err) = sftp.close().await {
+                warn!(%err, "error closing the SFTP client");
+            }
+        }
+    }
+}
+
+/// `permissions` with the execute bit added for the owner and group.
+fn with_execute_bits(mut permissions: Permissions) -> Permissions {
+    permissions
+        .set_execute_by_group(true)
+        .set_execute_by_owner(true);
+    permissions
+}
+
+/// Launches the SFTP subsystem. When it fails to start because the host has
+/// it disabled, the error suggests `"transfer": "scp"`.
+async fn open_sftp<'s>(session: &'s Session, host: &Host) -> Result<RemoteFs<'s>> {
+    let mut child = session
+        .subsystem("sftp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await
+        .map_err(|err| {
+            DeployError::Connection(format!("Unable to launch SFTP subsystem: {}", err))
+        })?;
+    let mut stderr = child.stderr().take().unwrap();
+
+    let sftp = match Sftp::new(
+        child.stdin().take().unwrap(),
+        child.stdout().take().unwrap(),
+        host.sftp().options(),
+    )
+    .await
+    {
+        Ok(sftp) => sftp,
+        Err(err) => {
+            let mut output = String::new();
+            let _ = timeout(Duration::from_secs(1), stderr.read_to_string(&mut output)).await;
+            // Retrying can't help with a disabled subsystem, so it's
+            // reported as the config problem it is.
+            if output.contains("subsystem request failed") {
+                return Err(DeployError::Config(format!(
+                    "Error starting SFTP client: {}\nThe host has the SFTP subsystem disabled, set `\"transfer\": \"scp\"` for it to upload with scp instead",
+                    err
+                )));
+            }
+            return Err(DeployError::Connection(format!(
+                "Error starting SFTP client: {}",
+                err
+            )));
+        }
+    };
+
+    // The pipe is only needed for the hint, anything else the subsystem
+    // writes is passed through.
+    tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut stderr, &mut tokio::io::stderr()).await;
+    });
+
+    Ok(RemoteFs::Sftp {
+        _child: Box::new(child),
+        sftp,
+        write_retries: host.sftp().write_retries(),
+    })
+}
+
+async fn set_local_mode(path: &str, mode: u32) -> Result<(), String> {
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Runs `test` with `args` on the host, returning whether it succeeded.
+async fn test(session: &Session, args: &[&str]) -> Result<bool, String> {
+    let out = unwrap_command_stderr(Remote::Ssh(session).command("test").args(args))
+        .await
+        .map_err(|err| err.to_string())?;
+    Ok(out.status.success())
+}
+
+async fn run(session: &Session, program: &str, args: &[&str]) -> Result<(), String> {
+    unwrap_command_stderr(Remote::Ssh(session).command(program).args(args))
+        .await
+        .map_err(|err| format!("Error running `{}` on host: {}", program, err))?
+        .check()
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn execute_bits_are_added_to_the_existing_mode() {
+        assert_eq!(
+            with_execute_bits(Permissions::from(0o644)),
+            Permissions::from(0o754)
+        );
+        // Others are left alone, without execute.
+        assert_eq!(
+            with_execute_bits(Permissions::from(0o600)),
+            Permissions::from(0o710)
+        );
+        assert_eq!(
+            with_execute_bits(Permissions::from(0o755)),
+            Permissions::from(0o755)
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use openssh::{Session, Stdio};
+use openssh_sftp_client::{Error as SftpError, Sftp};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::time::sleep;
+use tracing::warn;
+
+/// Default size of each SFTP write when streaming a file to the host.
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Default number of times a failed SFTP write is retried, see
+/// [`is_transient`].
+pub const WRITE_RETRIES: u32 = 3;
+
+/// Wait before the first retry of an SFTP write, doubled for each following
+/// one.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Whether an SFTP write failed in a way repeating it may get past: the
+/// connection's socket being momentarily unwritable (`EAGAIN`), interrupted
+/// or timing out. Every error the server answers with is fatal, including its
+/// generic `SSH_FX_FAILURE`, which OpenSSH sends for a full disk as a bare
+/// "Failure", as are a closed connection and protocol errors.
+pub fn is_transient(err: &SftpError) -> bool {
+    match err {
+        SftpError::IOError(err) => matches!(
+            err.kind(),
+            ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
+/// Whether the write that failed with `err` should be repeated, sleeping for
+/// the backoff first. `attempt` counts the retries made so far.
+pub async fn retry_write(err: &SftpError, attempt: &mut u32, retries: u32) -> bool {
+    if *attempt >= retries || !is_transient(err) {
+        return false;
+    }
+    *attempt += 1;
+    warn!(%err, attempt = *attempt, retries, "SFTP write failed, retrying");
+    sleep(WRITE_RETRY_DELAY * 2u32.pow(*attempt - 1)).await;
+    true
+}
+
+/// Streams the local file at `local` into `remote` over SFTP in `chunk_size`
+/// chunks, optionally pacing the writes to stay under `limit` bytes per
+/// second on average. Each chunk's write is retried up to `retries` times on
+/// transient errors. Returns the number of bytes written.
+pub async fn upload_file(
+    sftp: &Sftp,
+    local: &Path,
+    remote: &str,
+    limit: Option<u64>,
+    chunk_size: usize,
+    retries: u32,
+) -> Result<u64, String> {
+    let mut source = tokio::fs::File::open(local)
+        .await
+        .map_err(|err| format!("Error opening `{}`: {}", local.display(), err))?;
+    let mut target = sftp
+        .create(remote)
+        .await
+        .map_err(|err| format!("Error creating `{}` on host: {}", remote, err))?;
+
+    let mut limiter = limit.map(RateLimiter::new);
+    let mut buffer = vec![0; chunk_size];
+    let mut written = 0;
+
+    loop {
+        let n = source
+            .read(&mut buffer)
+            .await
+            .map_err(|err| format!("Error reading `{}`: {}", local.display(), err))?;
+        if n == 0 {
+            break;
+        }
+
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.acquire(n as u64).await;
+        }
+
+        // A failed write leaves the file's offset where it was, so it can
+        // be repeated as is.
+        let mut chunk = &buffer[..n];
+        let mut attempt = 0;
+        while !chunk.is_empty() {
+            match target.write(chunk).await {
+                Ok(n) => chunk = &chunk[n..],
+                Err(err) if retry_write(&err, &mut attempt, retries).await => {}
+                Err(err) => return Err(format!("Error writing `{}` on host: {}", remote, err)),
+            }
+        }
+        written += n as u64;
+    }
+
+    target
+        .close()
+        .await
+        .map_err(|err| format!("Error closing `{}` on host: {}", remote, err))?;
+
+    Ok(written)
+}
+
+/// Streams the local file at `local` into `remote` by running `scp -t` on
+/// the host and speaking its sink protocol, for hosts without SFTP. Writes
+/// are chunked and paced like [`upload_file`]. Returns the number of bytes
+/// written.
+pub async fn scp_file(
+    session: &Session,
+    local: &Path,
+    remote: &str,
+    limit: Option<u64>,
+    chunk_size: usize,
+) -> Result<u64, String> {
+    let source = tokio::fs::File::open(local)
+        .await
+        .map_err(|err| format!("Error opening `{}`: {}", local.display(), err))?;
+    let size = source
+        .metadata()
+        .await
+        .map_err(|err| format!("Error reading `{}`: {}", local.display(), err))?
+        .len();
+    let name = Path::new(remote)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("Invalid remote path `{}`", remote))?;
+
+    let mut child = session
+        .command("scp")
+        .args(["-t", remote])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .await
+        .map_err(|err| format!("Error starting `scp` on host: {}", err))?;
+    let mut stdin = child.stdin().take().unwrap();
+    let mut stdout = child.stdout().take().unwrap();
+
+    read_ack(&mut stdout).await?;
+    stdin
+        .write_all(format!("C0644 {} {}\n", size, name).as_bytes())
+        .await
+        .map_err(|err| format!("Error writing to `scp`: {}", err))?;
+    read_ack(&mut stdout).await?;
+
+    // Only the size announced above may be sent, even if the file grew since.
+    let mut source = source.take(size);
+    let mut limiter = limit.map(RateLimiter::new);
+    let mut buffer = vec![0; chunk_size];
+    let mut written = 0;
+
+    loop {
+        let n = source
+            .read(&mut buffer)
+            .await
+            .map_err(|err| format!("Error reading `{}`: {}", local.display(), err))?;
+        if n == 0 {
+            break;
+        }
+
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.acquire(n as u64).await;
+        }
+
+        stdin
+            .write_all(&buffer[..n])
+            .await
+            .map_err(|err| format!("Error writing `{}` on host: {}", remote, err))?;
+        written += n as u64;
+    }
+    if written != size {
+        return Err(format!(
+            "`{}` shrank while being uploaded, from {} to {} bytes",
+            local.display(),
+            size,
+            written
+        ));
+    }
+
+    stdin
+        .write_all(&[0])
+        .await
+        .map_err(|err| format!("Error writing to `scp`: {}", err))?;
+    read_ack(&mut stdout).await?;
+    drop(stdin);
+
+    let status = child
+        .wait()
+        .await
+        .map_err(|err| format!("Error waiting for `scp` on host: {}", err))?;
+    if !status.success() {
+        return Err(format!("`scp` exited with {}", status));
+    }
+
+    Ok(written)
+}
+
+/// Reads an `scp` reply: a zero byte, or an error flag followed by a message
+/// line.
+async fn read_ack(reader: &mut (impl AsyncRead + Unpin)) -> Result<(), String> {
+    let mut byte = [0];
+    reader
+        .read_exact(&mut byte)
+        .await
+        .map_err(|err| format!("Error reading `scp` reply: {}", err))?;
+    if byte[0] == 0 {
+        return Ok(());
+    }
+
+    let mut message = Vec::new();
+    loop {
+        let mut next = [0];
+        match reader.read_exact(&mut next).await {
+            Ok(_) if next[0] != b'\n' => message.push(next[0]),
+            _ => break,
+        }
+    }
+    Err(format!(
+        "`scp` on host: {}",
+        String::from_utf8_lossy(&message).trim()
+    ))
+}
+
+/// Token bucket allowing bursts of up to one second worth of bytes.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            rate: bytes_per_sec as f64,
+            tokens: bytes_per_sec as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `n` tokens, sleeping until the bucket has refilled enough when
+    /// it runs dry.
+    async fn acquire(&mut self, n: u64) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::error::{DeployError, Result};
+
+/// Variables available to `${VAR}` references in the deploy and hosts
+/// config. Values from `--env-file` files take precedence over the process
+/// environment, and later files over earlier ones. The process environment
+/// itself is never modified.
+#[derive(Debug, Default)]
+pub struct Vars {
+    files: HashMap<String, String>,
+}
+
+impl Vars {
+    /// Loads each dotenv-style `KEY=VALUE` file in order.
+    pub fn load(env_files: &[PathBuf]) -> Result<Vars> {
+        let mut files = HashMap::new();
+        for path in env_files {
+            let content = read_to_string(path).map_err(|err| {
+                DeployError::Config(format!("Error opening `{}`: {}", path.display(), err))
+            })?;
+            for (number, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let line = line.strip_prefix("export ").unwrap_or(line);
+                let (key, value) = line.split_once('=').ok_or_else(|| {
+                    DeployError::Config(format!(
+                        "Error parsing `{}` line {}: expected `KEY=VALUE`",
+                        path.display(),
+                        number + 1
+                    ))
+                })?;
+                files.insert(key.trim().to_string(), unquote(value.trim()).to_string());
+            }
+        }
+        Ok(Vars { files })
+    }
+
+    fn get(&self, name: &str) -> Option<String> {
+        self.files
+            .get(name)
+            .cloned()
+            .or_else(|| env::var(name).ok())
+    }
+
+    /// Replaces `${VAR}` references in every string of `value`. `$${` is
+    /// left as a literal `${`. Undefined variables are an error.
+    pub fn substitute(&self, value: &mut Value) -> Result<(), String> {
+        match value {
+            Value::String(s) => *s = self.substitute_str(s)?,
+            Value::Array(values) => {
+                for value in values {
+                    self.substitute(value)?;
+                }
+            }
+            Value::Object(map) => {
+                for value in map.values_mut() {
+                    self.substitute(value)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn substitute_str(&self, s: &str) -> Result<String, String> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            if let Some(after) = rest.strip_prefix("$${") {
+                out.push_str("${");
+                rest = after;
+            } else if let Some(after) = rest.strip_prefix("${") {
+                let end = after
+                    .find('}')
+                    .ok_or_else(|| format!("Unterminated `${{` in `{}`", s))?;
+                let name = &after[..end];
+                let value = self
+                    .get(name)
+                    .ok_or_else(|| format!("Variable `{}` is not set", name))?;
+                out.push_str(&value);
+                rest = &after[end + 1..];
+            } else {
+                out.push('$');
+                rest = &rest[1..];
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// Strips one pair of matching single or double quotes around a value.
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
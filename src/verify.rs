@@ -0,0 +1,81 @@
+use std::collections::HashSet;
+
+use crate::config::{App, Platform};
+use crate::error::{DeployError, Result};
+use crate::events::{emit, DeployEvent};
+use crate::manifest::Manifest;
+use crate::remote::{powershell, ps_quote, unwrap_command_stderr, Remote};
+
+/// Number of missing files named in the error, the rest being counted.
+const LISTED_MISSING: usize = 10;
+
+/// Checks that every file of the archive is under `host_path` after
+/// extraction, reporting how many of them are, for `verify_file_count`.
+/// Files already on the host that aren't in the archive aren't counted, so
+/// earlier deploys and hook output don't cause a mismatch.
+pub async fn verify_file_count(session: &Remote<'_>, app: &App, platform: Platform) -> Result<()> {
+    let manifest_path = Manifest::path_for(app.archive_path());
+    let manifest = Manifest::load(&manifest_path)
+        .ok_or_else(|| DeployError::Build(format!("Error reading manifest `{}`", manifest_path)))?;
+    let expected = manifest.hashes();
+
+    let mut list = match platform {
+        Platform::Unix => {
+            let mut command = session.command("cd");
+            command
+                .arg(app.host_path())
+                .raw_arg("&&")
+                .args(["find", ".", "-type", "f"]);
+            command
+        }
+        Platform::Windows => session.raw_command(powershell(&format!(
+            "Set-Location -LiteralPath {}; Get-ChildItem -Recurse -File -Force | \
+             ForEach-Object {{ Resolve-Path -Relative -LiteralPath $_.FullName }}",
+            ps_quote(app.host_path())
+        ))),
+    };
+    let out = unwrap_command_stderr(&mut list)
+        .await
+        .map_err(|err| DeployError::Remote(format!("Error running `find` on host: {}", err)))?
+        .check()
+        .map_err(|err| {
+            DeployError::Remote(format!(
+                "Error listing the files of `{}`:\n{}",
+                app.host_path(),
+                err
+            ))
+        })?;
+    let on_host: HashSet<String> = out
+        .stdout
+        .lines()
+        .map(|line| {
+            let path = line.trim_end_matches('\r').replace('\\', "/");
+            path.strip_prefix("./").unwrap_or(&path).to_string()
+        })
+        .collect();
+
+    let mut missing: Vec<&str> = expected
+        .keys()
+        .filter(|path| !on_host.contains(**path))
+        .copied()
+        .collect();
+    if missing.is_empty() {
+        emit(DeployEvent::FilesVerified {
+            count: expected.len(),
+        });
+        return Ok(());
+    }
+
+    missing.sort_unstable();
+    let mut listed = missing[..missing.len().min(LISTED_MISSING)].join(", ");
+    if missing.len() > LISTED_MISSING {
+        listed.push_str(&format!(" and {} more", missing.len() - LISTED_MISSING));
+    }
+    Err(DeployError::Remote(format!(
+        "Expected {} files from the archive under `{}`, found {}. Missing: {}",
+        expected.len(),
+        app.host_path(),
+        expected.len() - missing.len(),
+        listed
+    )))
+}
@@ -0,0 +1,87 @@
+use crate::changes::ChangeState;
+use crate::cli::Cli;
+use crate::config::{App, Host};
+use crate::deploy;
+use crate::error::{DeployError, Result};
+use crate::manifest::Manifest;
+use crate::output;
+use notify::{Event, RecursiveMode, Watcher};
+use std::env::current_dir;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::timeout;
+use tracing::warn;
+
+/// Deploys once, then redeploys every time the artifact paths change. Changes
+/// are debounced so a burst of writes triggers a single deploy, and changes
+/// made by the build itself are discarded once the deploy finishes.
+pub async fn watch(cli: &Cli, app: &App, hosts: &[Host], debounce: Duration) -> Result<()> {
+    let (tx, mut rx) = unbounded_channel();
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let _ = tx.send(res);
+    })
+    .map_err(|err| DeployError::Other(format!("Error starting file watcher: {}", err)))?;
+
+    for artifact in app.artifacts().iter().filter(|a| !a.is_url()) {
+        watcher
+            .watch(Path::new(artifact.src()), RecursiveMode::Recursive)
+            .map_err(|err| {
+                DeployError::Other(format!(
+                    "Error watching artifact `{}`: {}",
+                    artifact.src(),
+                    err
+                ))
+            })?;
+    }
+
+    let dir = current_dir()
+        .map_err(|err| DeployError::Other(format!("Error reading current directory: {}", err)))?;
+    // The archive and what goes with it are written to a temporary
+    // directory, only the state of past deploys stays here.
+    let mut outputs = vec![dir.join(ChangeState::path_for(app.archive_name()))];
+    for host in hosts {
+        outputs.push(dir.join(Manifest::deployed_path(app.archive_name(), host.name())));
+    }
+
+    deploy_once(cli, app, hosts).await;
+
+    println!(
+        "Watching {} artifact(s) for changes...",
+        app.artifacts().len()
+    );
+
+    while let Some(res) = rx.recv().await {
+        let event = match res {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("file watcher error: {}", err);
+                continue;
+            }
+        };
+
+        let relevant = (event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove())
+            && event.paths.iter().any(|p| !outputs.contains(p));
+        if !relevant {
+            continue;
+        }
+
+        while let Ok(Some(_)) = timeout(debounce, rx.recv()).await {}
+
+        println!("Change detected, redeploying...");
+        deploy_once(cli, app, hosts).await;
+
+        while rx.try_recv().is_ok() {}
+    }
+
+    Ok(())
+}
+
+/// Runs a deploy, reporting a failure instead of ending the watch loop.
+async fn deploy_once(cli: &Cli, app: &App, hosts: &[Host]) {
+    if let Err(err) = deploy(cli, app, hosts).await {
+        output::failure(format!("Deploy failed: {}", err));
+        println!("Waiting for changes...");
+    }
+}